@@ -7,12 +7,37 @@
 //! should consider how they wish to expose the agent to the app. Which protocol to use (HTTP for
 //! example) and what endpoints to expose (get and set for example).
 //!
-//! The [default agent] implementation exposes an HTTP GET and PUT endpoints to allow an app to get
-//! and set key/value pairs to the state. See the [default agent] implementation documentation below for more details.
+//! The [default agent] implementation exposes an HTTP GET, PUT and DELETE endpoints to allow an app
+//! to get, set and delete key/value pairs to the state. See the [default agent] implementation documentation below for more details.
+//!
+//! The [WebSocket agent] implementation multiplexes commands over a single connection and pushes
+//! the changes of the state to the app.
+//!
+//! The [RESP agent] implementation speaks a subset of the Redis protocol so apps can use an
+//! existing Redis client to access the state.
+//!
+//! The [memcached agent] implementation speaks the memcached text protocol for apps that only
+//! have a memcached client.
+//!
+//! The [gRPC agent] implementation exposes a typed gRPC service that is published as
+//! `proto/c19.proto`.
+//!
+//! The [GraphQL agent] implementation allows an app to select and shape many values in a single
+//! round trip and to subscribe to changes.
 //!
 //! [default agent]: crate::agent::default
+//! [WebSocket agent]: crate::agent::websocket
+//! [RESP agent]: crate::agent::resp
+//! [memcached agent]: crate::agent::memcached
+//! [gRPC agent]: crate::agent::grpc
+//! [GraphQL agent]: crate::agent::graphql
 
 pub mod default;
+pub mod graphql;
+pub mod grpc;
+pub mod memcached;
+pub mod resp;
+pub mod websocket;
 
 use crate::state;
 use futures::future::BoxFuture;
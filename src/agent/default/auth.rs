@@ -0,0 +1,429 @@
+//! Authentication of the requests to the agent.
+//!
+//! When `auth` is configured, every request must carry a bearer token in the `Authorization`
+//! header. Each token has a set of scopes:
+//!
+//! - `read` - allows getting, listing and watching values (`GET` requests and `POST /_mget`).
+//! - `write` - allows setting, patching, deleting and importing values.
+//!
+//! A request without a known token is rejected with `401` (unauthorized) and a request with a
+//! token that lacks the scope of the operation is rejected with `403` (forbidden).
+//!
+//! # Access control
+//! When several apps share the state, each client can be limited to the keys under a list of
+//! `prefixes`. Reads and writes of other keys are rejected with `403` (forbidden), and listings
+//! (`GET /_keys`, `GET /<prefix>/?recursive`) and watches (`GET /_changes`) only show the keys
+//! the client is allowed to see. A batch (`PUT /` or `POST /_mget`) is rejected as a whole if it
+//! holds a key outside the prefixes. Exporting and importing the whole state require access to
+//! all keys. A client without `prefixes` can access all keys.
+//!
+//! Clients that connect over the Unix domain socket can be identified by the credentials of their
+//! process instead of a token, with `peers` rules that match a `uid`, a `gid` or both. A request
+//! that carries a token is always identified by the token.
+//!
+//! # Configuration
+//!
+//! ```yaml
+//! agent:
+//!   kind: Default
+//!   auth:
+//!     tokens:
+//!       - token: 7c4a8d09ca3762af61e59520943dc264
+//!         scopes: [read, write]
+//!       - token: 0b4e7a0e5fe84ad35fb5f95b9ceeac79
+//!         scopes: [read]
+//!         prefixes: ["billing/", "shared/"]
+//!     tokens_file: /etc/c19/tokens.yaml
+//!     peers:
+//!       - uid: 1000
+//!         scopes: [read, write]
+//!         prefixes: ["billing/"]
+//! ```
+//!
+//! Tokens can be listed in the configuration, loaded from `tokens_file` or both. The file holds a
+//! list of tokens in the same format as `tokens`, so it can be mounted from a Kubernetes secret.
+//! The file is checked for changes every few seconds and reloaded, so tokens can be rotated
+//! without restarting the agent. A file that fails to reload is reported and the tokens that were
+//! loaded last are kept.
+
+use super::peer::{Match, Peer};
+use crate::helpers::http::responses::Responses;
+use http::{Request, Response};
+use hyper::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::Body;
+use log::{info, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time;
+
+type Result<T> = std::result::Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// The interval in which the tokens file is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Replaces the tokens when the configuration is printed.
+const REDACTED: &str = "<redacted>";
+
+/// An operation a token can be allowed to perform.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    Write,
+}
+
+/// A bearer token and the operations it allows.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Token {
+    /// The token itself. It is redacted when the configuration is printed.
+    #[serde(serialize_with = "redact")]
+    token: String,
+    scopes: Vec<Scope>,
+
+    /// The key prefixes the token can access. All keys if missing.
+    #[serde(default)]
+    prefixes: Option<Vec<String>>,
+}
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Token")
+            .field("token", &REDACTED)
+            .field("scopes", &self.scopes)
+            .field("prefixes", &self.prefixes)
+            .finish()
+    }
+}
+
+/// A rule that identifies Unix domain socket peers by their credentials.
+///
+/// A peer matches the rule if its uid and its gid match the ones that are set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerRule {
+    #[serde(flatten)]
+    credentials: Match,
+
+    scopes: Vec<Scope>,
+
+    /// The key prefixes the peer can access. All keys if missing.
+    #[serde(default)]
+    prefixes: Option<Vec<String>>,
+}
+
+/// The tokens that were loaded from the tokens file and the modification time of the file.
+#[derive(Debug, Default)]
+struct Loaded {
+    tokens: Vec<Token>,
+    modified: Option<SystemTime>,
+}
+
+/// The authentication configuration of the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Auth {
+    /// The static tokens.
+    /// Default value: none.
+    tokens: Vec<Token>,
+
+    /// A YAML file with more tokens, in the same format as `tokens`.
+    /// Default value: None.
+    tokens_file: Option<String>,
+
+    /// Rules that identify Unix domain socket peers by their credentials.
+    /// Default value: none.
+    peers: Vec<PeerRule>,
+
+    #[serde(skip)]
+    loaded: Arc<RwLock<Loaded>>,
+}
+
+/// What an authorized client can access.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Grant {
+    /// The key prefixes the client can access, or `None` for all keys.
+    prefixes: Option<Vec<String>>,
+}
+
+impl Grant {
+    /// Returns true if the client can access the key.
+    pub fn allows(&self, key: &str) -> bool {
+        match &self.prefixes {
+            Some(prefixes) => prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())),
+            None => true,
+        }
+    }
+
+    /// Returns true if the client can access all keys.
+    pub fn allows_all(&self) -> bool {
+        self.prefixes.is_none()
+    }
+
+    /// Returns the key prefixes the client can access, or `None` for all keys.
+    pub fn prefixes(&self) -> Option<Vec<String>> {
+        self.prefixes.clone()
+    }
+}
+
+/// The reason a request was denied.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denied {
+    /// The request does not carry a known token.
+    Unauthenticated,
+
+    /// The token does not allow the operation.
+    Forbidden,
+}
+
+impl Denied {
+    /// Returns the response to reject the request with.
+    pub fn response(self) -> Response<Body> {
+        let (mut response, challenge) = match self {
+            Denied::Unauthenticated => (Responses::unauthorized(None), "Bearer realm=\"c19\""),
+            Denied::Forbidden => (Responses::forbidden(None), "Bearer realm=\"c19\", error=\"insufficient_scope\""),
+        };
+
+        response.headers_mut().insert(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+        response
+    }
+}
+
+impl Auth {
+    /// Loads the tokens file, if one is configured.
+    ///
+    /// Fails if the file cannot be read or parsed, so a broken configuration is found on start.
+    pub fn init(&self) -> Result<()> {
+        if let Some(path) = &self.tokens_file {
+            self.load(path)?;
+        }
+
+        if self.peers.iter().any(|rule| rule.credentials.is_empty()) {
+            return Err("a peers rule must set a uid, a gid or both".into());
+        }
+
+        if self.tokens.is_empty() && self.peers.is_empty() && self.loaded.read().unwrap().tokens.is_empty() {
+            warn!("No tokens are configured for the agent; all requests will be rejected");
+        }
+
+        Ok(())
+    }
+
+    /// Reloads the tokens file whenever it changes.
+    ///
+    /// Returns right away if no tokens file is configured.
+    pub async fn watch(&self) -> Result<()> {
+        let path = match &self.tokens_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        loop {
+            time::delay_for(RELOAD_INTERVAL).await;
+
+            let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+            if modified.is_some() && modified == self.loaded.read().unwrap().modified {
+                continue;
+            }
+
+            match self.load(path) {
+                Ok(count) => info!("Reloaded {} tokens from {}", count, path),
+                Err(e) => warn!("Failed to reload the tokens from {}; {}", path, e),
+            }
+        }
+    }
+
+    /// Checks that the client is allowed the specified scope and returns what it can access.
+    ///
+    /// The client is identified by the token of the request or, if the request has no
+    /// `Authorization` header, by the first `peers` rule that matches the peer.
+    pub fn authorize<B>(&self, req: &Request<B>, peer: Peer, scope: Scope) -> std::result::Result<Grant, Denied> {
+        let header = req.headers().get(AUTHORIZATION);
+        let (scopes, prefixes) = match header {
+            Some(header) => {
+                let token = header.to_str().ok().and_then(bearer).ok_or(Denied::Unauthenticated)?;
+                let loaded = self.loaded.read().unwrap();
+                let token = self
+                    .tokens
+                    .iter()
+                    .chain(loaded.tokens.iter())
+                    .find(|t| constant_time_eq(t.token.as_bytes(), token.as_bytes()))
+                    .ok_or(Denied::Unauthenticated)?;
+
+                (token.scopes.clone(), token.prefixes.clone())
+            }
+            None => {
+                let rule = self.peers.iter().find(|rule| rule.credentials.matches(peer)).ok_or(Denied::Unauthenticated)?;
+                (rule.scopes.clone(), rule.prefixes.clone())
+            }
+        };
+
+        if scopes.contains(&scope) {
+            Ok(Grant { prefixes })
+        } else {
+            Err(Denied::Forbidden)
+        }
+    }
+
+    /// Loads the tokens file and returns the number of tokens in it.
+    fn load(&self, path: &str) -> Result<usize> {
+        let read = |e| format!("failed to read the tokens file {}; {}", path, e);
+        let modified = fs::metadata(path).map_err(read)?.modified().ok();
+        let tokens: Vec<Token> = serde_yaml::from_str(&fs::read_to_string(path).map_err(read)?)
+            .map_err(|e| format!("invalid tokens file {}; {}", path, e))?;
+
+        let count = tokens.len();
+        *self.loaded.write().unwrap() = Loaded { tokens, modified };
+
+        Ok(count)
+    }
+}
+
+fn redact<S: Serializer>(_: &str, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_str(REDACTED)
+}
+
+/// Returns the token of a bearer `Authorization` header.
+fn bearer(header: &str) -> Option<&str> {
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+        _ => None,
+    }
+}
+
+/// Compares two tokens in a time that does not depend on where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::peer::Credentials;
+    use super::*;
+
+    fn auth(yaml: &str) -> Auth {
+        let auth: Auth = serde_yaml::from_str(yaml).unwrap();
+        auth.init().unwrap();
+        auth
+    }
+
+    fn request(authorization: Option<&str>) -> Request<()> {
+        let mut req = Request::get("/cat");
+        if let Some(authorization) = authorization {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+
+        req.body(()).unwrap()
+    }
+
+    fn tcp() -> Peer {
+        Peer::Tcp("127.0.0.1:4242".parse().unwrap())
+    }
+
+    #[test]
+    fn should_parse_bearer_tokens() {
+        assert_eq!(bearer("Bearer garfield"), Some("garfield"));
+        assert_eq!(bearer("bearer  garfield "), Some("garfield"));
+        assert_eq!(bearer("Basic Z2FyZmllbGQ="), None);
+        assert_eq!(bearer("Bearer"), None);
+        assert_eq!(bearer(""), None);
+    }
+
+    #[test]
+    fn should_compare_tokens() {
+        assert!(constant_time_eq(b"garfield", b"garfield"));
+        assert!(!constant_time_eq(b"garfield", b"garfielt"));
+        assert!(!constant_time_eq(b"garfield", b"garf"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+    #[test]
+    fn should_authorize_tokens_by_scope() {
+        let auth = auth(
+            r#"
+            tokens:
+              - token: garfield
+                scopes: [read, write]
+              - token: odie
+                scopes: [read]
+                prefixes: ["dogs/"]
+            "#,
+        );
+
+        assert_eq!(auth.authorize(&request(Some("Bearer garfield")), tcp(), Scope::Write), Ok(Grant::default()));
+        assert_eq!(
+            auth.authorize(&request(Some("Bearer odie")), tcp(), Scope::Read),
+            Ok(Grant { prefixes: Some(vec!["dogs/".to_string()]) })
+        );
+        assert_eq!(auth.authorize(&request(Some("Bearer odie")), tcp(), Scope::Write), Err(Denied::Forbidden));
+        assert_eq!(auth.authorize(&request(Some("Bearer nermal")), tcp(), Scope::Read), Err(Denied::Unauthenticated));
+        assert_eq!(auth.authorize(&request(Some("Basic garfield")), tcp(), Scope::Read), Err(Denied::Unauthenticated));
+        assert_eq!(auth.authorize(&request(None), tcp(), Scope::Read), Err(Denied::Unauthenticated));
+
+        assert_eq!(Denied::Unauthenticated.response().status(), http::StatusCode::UNAUTHORIZED);
+        assert_eq!(Denied::Forbidden.response().status(), http::StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn should_authorize_peers_by_credentials() {
+        let auth = auth(
+            r#"
+            tokens:
+              - token: odie
+                scopes: [read]
+            peers:
+              - uid: 1000
+                scopes: [read, write]
+              - gid: 100
+                scopes: [read]
+            "#,
+        );
+
+        let peer = |uid, gid| Peer::Unix(Some(Credentials { uid, gid }));
+        assert!(auth.authorize(&request(None), peer(1000, 1000), Scope::Write).is_ok());
+        assert_eq!(auth.authorize(&request(None), peer(1001, 100), Scope::Write), Err(Denied::Forbidden));
+        assert_eq!(auth.authorize(&request(None), peer(1001, 1001), Scope::Read), Err(Denied::Unauthenticated));
+        assert_eq!(auth.authorize(&request(None), Peer::Unix(None), Scope::Read), Err(Denied::Unauthenticated));
+
+        // a token always identifies the client, even over the socket
+        let req = request(Some("Bearer odie"));
+        assert_eq!(auth.authorize(&req, peer(1000, 1000), Scope::Write), Err(Denied::Forbidden));
+    }
+
+    #[test]
+    fn should_reject_peers_rules_without_credentials() {
+        let auth: Auth = serde_yaml::from_str("peers: [{scopes: [read]}]").unwrap();
+        assert!(auth.init().is_err());
+    }
+
+    #[test]
+    fn should_reload_the_tokens_file() {
+        let path = std::env::temp_dir().join(format!("c19-tokens-{}.yaml", std::process::id()));
+        fs::write(&path, "- token: garfield\n  scopes: [read]\n").unwrap();
+        let auth = auth(&format!("tokens_file: {}", path.display()));
+        assert!(auth.authorize(&request(Some("Bearer garfield")), tcp(), Scope::Read).is_ok());
+
+        fs::write(&path, "- token: odie\n  scopes: [read]\n").unwrap();
+        assert_eq!(auth.load(path.to_str().unwrap()).unwrap(), 1);
+        assert_eq!(auth.authorize(&request(Some("Bearer garfield")), tcp(), Scope::Read), Err(Denied::Unauthenticated));
+        assert!(auth.authorize(&request(Some("Bearer odie")), tcp(), Scope::Read).is_ok());
+
+        // a file that fails to load keeps the tokens that were loaded last
+        fs::write(&path, "- token: [").unwrap();
+        assert!(auth.load(path.to_str().unwrap()).is_err());
+        assert!(auth.authorize(&request(Some("Bearer odie")), tcp(), Scope::Read).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn should_redact_tokens() {
+        let auth = auth("tokens: [{token: garfield, scopes: [read]}]");
+        assert!(!serde_yaml::to_string(&auth).unwrap().contains("garfield"));
+        assert!(serde_yaml::to_string(&auth).unwrap().contains(REDACTED));
+        assert!(!format!("{:?}", auth).contains("garfield"));
+    }
+}
@@ -0,0 +1,323 @@
+//! A stream of the changes committed to the state.
+//!
+//! `GET /_changes?prefix=<prefix>&snapshot`
+//!
+//! Streams every change that is committed to the state as [Server-Sent Events]. Each event
+//! carries the id of the change, its kind (`set`, `delete` or `expire`) and a JSON object with
+//! the key, the timestamp and the new value of the key (`null` for deletes and expirations):
+//!
+//! ```
+//! id: 42
+//! event: set
+//! data: {"key":"cat","ts":1601241450390,"value":{"ts":1601241450390,"ttl":null,"value":"garfield"}}
+//! ```
+//!
+//! Only changes to keys that start with `prefix` and that the client is allowed to see are
+//! streamed. When `snapshot` is set, the stream starts with a `snapshot` event that holds all the
+//! values under `prefix` (the same as listing the keys with their values).
+//!
+//! # Resuming
+//! A client can resume the stream from the last event it has seen by sending its id in the
+//! `Last-Event-ID` header or the `last_event_id` query parameter. The changes since that event are
+//! sent first. If the state no longer keeps those changes, a `snapshot` event is sent instead.
+//!
+//! A `snapshot` event is sent as well whenever the client falls too far behind the changes, so
+//! clients should treat a `snapshot` event as a reset of their view of the state. The id of a
+//! `snapshot` event is the id of the last change it includes, so a client can resume after it like
+//! after any other event. Changes that are committed while the snapshot is read may be sent again
+//! right after it.
+//!
+//! [Server-Sent Events]: https://html.spec.whatwg.org/multipage/server-sent-events.html
+
+use super::auth::Grant;
+use crate::helpers::http::query::Query;
+use crate::helpers::http::responses::Responses;
+use crate::state::{self, Change, ChangeKind};
+use http::{Request, Response};
+use hyper::body::{Bytes, Sender};
+use hyper::{http::header::HeaderValue, Body};
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::broadcast::RecvError;
+use tokio::time;
+
+/// The interval in which a comment is sent to keep an idle stream alive.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Streams the changes of the state as Server-Sent Events.
+///
+/// Returns 400 (bad request) if the state does not support change notification or if the last
+/// event id is invalid.
+pub fn handler(state: state::SafeState, req: &Request<Body>, grant: Grant) -> Response<Body> {
+    let query = Query::new(req);
+    let prefix = query.get("prefix").unwrap_or_default().to_string();
+
+    let last_event_id = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .or_else(|| query.get("last_event_id"));
+    let last_event_id = match last_event_id.map(|id| id.parse::<u64>()).transpose() {
+        Ok(id) => id,
+        Err(_) => return Responses::bad_request(Some("invalid last event id".into())),
+    };
+
+    let changes = match state.subscribe() {
+        Some(changes) => changes,
+        None => return Responses::bad_request(Some("the state does not support change notification".into())),
+    };
+
+    // resume from the last event id or fall back to a snapshot if we can't
+    let backlog = last_event_id.and_then(|id| state.changes_since(id));
+    let snapshot = query.flag("snapshot") || (last_event_id.is_some() && backlog.is_none());
+
+    let (sender, body) = Body::channel();
+    let stream = Stream { state, prefix, grant, sender, last_id: last_event_id.unwrap_or(0) };
+    tokio::spawn(stream.run(changes, backlog.unwrap_or_default(), snapshot));
+
+    let mut response = Responses::ok(body);
+    let headers = response.headers_mut();
+    headers.insert("Content-Type", HeaderValue::from_static("text/event-stream"));
+    headers.insert("Cache-Control", HeaderValue::from_static("no-cache"));
+
+    response
+}
+
+/// A single stream of changes to a client.
+struct Stream {
+    state: state::SafeState,
+    prefix: String,
+    grant: Grant,
+    sender: Sender,
+
+    /// The id of the last change sent to the client.
+    last_id: u64,
+}
+
+impl Stream {
+    /// Sends the snapshot and the backlog and then streams the changes until the client
+    /// disconnects.
+    async fn run(mut self, mut changes: tokio::sync::broadcast::Receiver<Change>, backlog: Vec<Change>, snapshot: bool) {
+        if snapshot && self.snapshot().await.is_err() {
+            return;
+        }
+
+        for change in backlog {
+            if self.send(change).await.is_err() {
+                return;
+            }
+        }
+
+        loop {
+            let result = match time::timeout(KEEP_ALIVE_INTERVAL, changes.recv()).await {
+                Ok(Ok(change)) => self.send(change).await,
+                Ok(Err(RecvError::Lagged(_))) => self.catch_up().await,
+                Ok(Err(RecvError::Closed)) => return,
+                Err(_) => self.sender.send_data(Bytes::from_static(b":\n\n")).await,
+            };
+
+            if result.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Sends the changes that were missed, or a new snapshot if they are no longer kept.
+    async fn catch_up(&mut self) -> hyper::Result<()> {
+        match self.state.changes_since(self.last_id) {
+            Some(changes) => {
+                for change in changes {
+                    self.send(change).await?;
+                }
+                Ok(())
+            }
+            None => self.snapshot().await,
+        }
+    }
+
+    /// Sends all the values under the prefix as a `snapshot` event.
+    ///
+    /// The id of the last change is read before the values, so the changes after it are sent
+    /// once the snapshot is, even if the snapshot already includes some of them.
+    async fn snapshot(&mut self) -> hyper::Result<()> {
+        self.last_id = self.state.last_change_id().unwrap_or(self.last_id);
+
+        let scan = state::Scan {
+            prefix: self.prefix.clone(),
+            limit: None,
+            cursor: None,
+            values: true,
+            within: self.grant.prefixes(),
+        };
+        let data = self
+            .state
+            .scan(&scan)
+            .ok()
+            .and_then(|page| page.as_bytes())
+            .unwrap_or_default();
+
+        let event = format!("id: {}\nevent: snapshot\ndata: {}\n\n", self.last_id, String::from_utf8_lossy(&data));
+        self.sender.send_data(event.into()).await
+    }
+
+    /// Sends a change to the client.
+    ///
+    /// Changes that were already sent, that do not match the prefix or that the client is not
+    /// allowed to see are skipped.
+    async fn send(&mut self, change: Change) -> hyper::Result<()> {
+        if change.id <= self.last_id {
+            return Ok(());
+        }
+        self.last_id = change.id;
+
+        if !change.key.starts_with(&self.prefix) || !self.grant.allows(&change.key) {
+            return Ok(());
+        }
+
+        let value: serde_json::Value = change
+            .value
+            .and_then(|value| value.as_bytes())
+            .and_then(|value| serde_json::from_slice(&value).ok())
+            .unwrap_or_default();
+
+        let kind = match change.kind {
+            ChangeKind::Set => "set",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Expire => "expire",
+        };
+
+        let data = json!({"key": change.key, "ts": change.ts, "value": value});
+        let event = format!("id: {}\nevent: {}\ndata: {}\n\n", change.id, kind, data);
+
+        self.sender.send_data(event.into()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::{Condition, StateValue};
+    use hyper::body::HttpBody;
+    use std::sync::Arc;
+
+    fn state() -> state::SafeState {
+        Arc::new(state::default::Default::default())
+    }
+
+    fn set(state: &state::SafeState, key: &str) -> u64 {
+        state.insert_if(&key.to_string() as &dyn StateValue, &"1", None, Condition::Always).unwrap().unwrap()
+    }
+
+    fn stream(state: &state::SafeState, uri: &str, last_event_id: Option<&str>) -> Response<Body> {
+        let mut req = Request::get(uri);
+        if let Some(id) = last_event_id {
+            req = req.header("Last-Event-ID", id);
+        }
+
+        handler(state.clone(), &req.body(Body::empty()).unwrap(), Grant::default())
+    }
+
+    /// Reads the next event of a stream and returns its id, its kind and its data.
+    async fn event(response: &mut Response<Body>) -> (u64, String, serde_json::Value) {
+        let chunk = time::timeout(Duration::from_secs(1), response.body_mut().data()).await.unwrap().unwrap().unwrap();
+        let event = String::from_utf8(chunk.to_vec()).unwrap();
+        let fields: Vec<&str> = event.trim_end().splitn(3, '\n').collect();
+
+        assert!(event.ends_with("\n\n"), "{}", event);
+        assert_eq!(fields.len(), 3, "{}", event);
+        (
+            fields[0].trim_start_matches("id: ").parse().unwrap(),
+            fields[1].trim_start_matches("event: ").to_string(),
+            serde_json::from_str(fields[2].trim_start_matches("data: ")).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn should_stream_the_changes_of_a_prefix() {
+        let state = state();
+        let mut response = stream(&state, "/_changes?prefix=cat/", None);
+        assert_eq!(response.headers()["Content-Type"], "text/event-stream");
+
+        set(&state, "dog");
+        let ts = set(&state, "cat/garfield");
+        let deleted = state.delete_if(&"cat/garfield".to_string(), Condition::Exists).unwrap().unwrap();
+
+        let (id, kind, data) = event(&mut response).await;
+        assert_eq!((id, kind.as_str()), (2, "set"));
+        assert_eq!(data["key"], "cat/garfield");
+        assert_eq!(data["ts"], ts);
+        assert_eq!(data["value"]["value"], 1);
+
+        let (id, kind, data) = event(&mut response).await;
+        assert_eq!((id, kind.as_str()), (3, "delete"));
+        assert_eq!(data, json!({"key": "cat/garfield", "ts": deleted, "value": null}));
+    }
+
+    #[tokio::test]
+    async fn should_resume_from_the_last_event_id() {
+        let state = state();
+        set(&state, "cat/garfield");
+        set(&state, "dog/odie");
+        set(&state, "cat/tom");
+
+        let mut response = stream(&state, "/_changes?prefix=cat/", Some("1"));
+        set(&state, "cat/nermal");
+        assert_eq!(event(&mut response).await.0, 3);
+        assert_eq!(event(&mut response).await.0, 4);
+
+        let mut response = stream(&state, "/_changes?last_event_id=3", None);
+        assert_eq!(event(&mut response).await.0, 4);
+
+        let response = stream(&state, "/_changes", Some("garfield"));
+        assert_eq!(response.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn should_start_with_a_snapshot() {
+        let state = state();
+        set(&state, "cat/garfield");
+        set(&state, "dog/odie");
+
+        let mut response = stream(&state, "/_changes?prefix=cat/&snapshot", None);
+        let (id, kind, data) = event(&mut response).await;
+        assert_eq!((id, kind.as_str()), (2, "snapshot"));
+        assert_eq!(data["values"].as_object().unwrap().keys().collect::<Vec<_>>(), vec!["cat/garfield"]);
+
+        set(&state, "cat/tom");
+        assert_eq!(event(&mut response).await.0, 3);
+    }
+
+    #[tokio::test]
+    async fn should_send_a_snapshot_once_the_backlog_is_gone() {
+        let state = state();
+        for i in 0..1100 {
+            set(&state, &format!("cat/{}", i % 10));
+        }
+
+        // the changes since the last event id are no longer kept
+        let mut response = stream(&state, "/_changes?prefix=cat/", Some("1"));
+        let (id, kind, data) = event(&mut response).await;
+        assert_eq!((id, kind.as_str()), (1100, "snapshot"));
+        assert_eq!(data["values"].as_object().unwrap().len(), 10);
+
+        set(&state, "cat/garfield");
+        assert_eq!(event(&mut response).await.0, 1101);
+    }
+
+    #[tokio::test]
+    async fn should_skip_the_changes_of_a_snapshot_after_falling_behind() {
+        let state = state();
+        let mut response = stream(&state, "/_changes?prefix=cat/", None);
+
+        // the stream does not run until the test awaits, so it falls behind the changes
+        for i in 0..1100 {
+            set(&state, &format!("cat/{}", i % 10));
+        }
+
+        let (id, kind, _) = event(&mut response).await;
+        assert_eq!((id, kind.as_str()), (1100, "snapshot"));
+
+        set(&state, "cat/garfield");
+        assert_eq!(event(&mut response).await.0, 1101);
+    }
+}
@@ -0,0 +1,396 @@
+//! Limits on the size and the rate of the requests to the agent.
+//!
+//! Without limits, a buggy client can push a huge payload or flood the state with writes faster
+//! than it can commit them. `limits` protects the agent from such clients:
+//!
+//! - `max_body` - the maximum size in bytes of a request body.
+//! - `max_key_length` - the maximum length in bytes of a key that is written.
+//! - `max_value_size` - the maximum size in bytes of a value that is written. For `PUT /<key>`
+//!   and `PATCH /<key>` this is the size of the body. For a batch (`PUT /`) this is the size of
+//!   every entry of the batch as JSON, and for an import the size of the `value` of every record
+//!   as JSON.
+//! - `requests_per_second` - the maximum rate of requests per client. Short bursts of up to
+//!   `burst` requests are allowed (by default, as many as `requests_per_second`). The rate must be
+//!   greater than 0, and the agent refuses to start otherwise.
+//!
+//! ```yaml
+//! agent:
+//!   kind: Default
+//!   limits:
+//!     max_body: 1048576
+//!     max_key_length: 256
+//!     max_value_size: 65536
+//!     requests_per_second: 100
+//!     burst: 500
+//! ```
+//!
+//! Requests over a size limit are rejected with `413` (payload too large) and requests over the
+//! rate with `429` (too many requests) and a `Retry-After` header. All limits are optional.
+//!
+//! Clients are told apart by their IP address, or by the uid of their process when they connect
+//! over the Unix domain socket. IPv6 clients are told apart by their `/64` prefix, since a single
+//! host usually holds a whole `/64`, and IPv4-mapped IPv6 clients by their IPv4 address. The rates
+//! of up to 10000 clients are kept, and the clients that were idle the longest are forgotten first.
+//!
+//! Imports (`POST /_import`) are streamed to the state in batches, so their body can be larger
+//! than `max_body`. Instead, every line of an import must fit `max_body`, and the key and the
+//! value of every record must fit `max_key_length` and `max_value_size`.
+
+use super::peer::{is_mapped, Peer};
+use crate::helpers::http::format;
+use crate::helpers::http::path;
+use crate::helpers::http::responses::Responses;
+use crate::state;
+use futures::StreamExt;
+use http::{Method, Request, Response};
+use hyper::header::{HeaderValue, CONTENT_LENGTH, RETRY_AFTER};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error as StdError;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The maximum number of clients whose rate is kept.
+const MAX_CLIENTS: usize = 10000;
+
+/// The limits configuration of the agent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Limits {
+    max_body: Option<usize>,
+    max_key_length: Option<usize>,
+    max_value_size: Option<usize>,
+    requests_per_second: Option<u32>,
+    burst: Option<u32>,
+
+    /// The rate of every client that sent requests recently.
+    #[serde(skip)]
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+/// A client, as far as rate limiting is concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Client {
+    Ip(IpAddr),
+    Uid(Option<u32>),
+}
+
+impl From<Peer> for Client {
+    /// Identifies IPv6 clients by their `/64` prefix, unless they are IPv4-mapped addresses
+    /// (`::ffff:a.b.c.d`) of IPv4 clients.
+    fn from(peer: Peer) -> Self {
+        match peer {
+            Peer::Tcp(addr) => match addr.ip() {
+                IpAddr::V6(v6) => match v6.to_ipv4() {
+                    Some(v4) if is_mapped(v6.segments()) => Client::Ip(IpAddr::V4(v4)),
+                    _ => Client::Ip(IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !u128::from(u64::MAX)))),
+                },
+                ip => Client::Ip(ip),
+            },
+            Peer::Unix(credentials) => Client::Uid(credentials.map(|credentials| credentials.uid)),
+        }
+    }
+}
+
+/// The buckets of the clients, along with the order in which they were last used.
+#[derive(Debug, Default)]
+struct Buckets {
+    buckets: HashMap<Client, Bucket>,
+    used: BTreeSet<(Instant, Client)>,
+}
+
+impl Buckets {
+    /// Takes a request of a client from its bucket, after filling the bucket up for the time that
+    /// passed since it was last used. Returns false if the bucket is empty.
+    ///
+    /// A new client starts with a full bucket. Once there are [MAX_CLIENTS], the client that was
+    /// idle the longest is forgotten to make room for a new one.
+    fn take(&mut self, client: Client, burst: f64, per_second: f64, now: Instant) -> bool {
+        match self.buckets.get(&client) {
+            Some(bucket) => {
+                self.used.remove(&(bucket.updated, client));
+            }
+            None if self.buckets.len() >= MAX_CLIENTS => {
+                if let Some(idle) = self.used.iter().next().copied() {
+                    self.used.remove(&idle);
+                    self.buckets.remove(&idle.1);
+                }
+            }
+            None => {}
+        }
+
+        self.used.insert((now, client));
+        let bucket = self.buckets.entry(client).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = burst.min(bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+/// A token bucket that holds the requests a client can still send right away.
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// A limit that a request went over.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exceeded {
+    Body(usize),
+    Line(usize),
+    Key(usize),
+    Value(String, usize),
+    Rate(u32),
+}
+
+impl Exceeded {
+    /// Returns the response to reject the request with.
+    pub fn response(self) -> Response<Body> {
+        match self {
+            Exceeded::Body(max) => {
+                Responses::payload_too_large(Some(format!("the body is larger than {} bytes", max).into()))
+            }
+            Exceeded::Line(max) => {
+                Responses::payload_too_large(Some(format!("a line is longer than {} bytes", max).into()))
+            }
+            Exceeded::Key(max) => {
+                Responses::payload_too_large(Some(format!("the key is longer than {} bytes", max).into()))
+            }
+            Exceeded::Value(key, max) => {
+                Responses::payload_too_large(Some(format!("the value of '{}' is larger than {} bytes", key, max).into()))
+            }
+            Exceeded::Rate(rate) => {
+                let mut response = Responses::too_many_requests(Some(
+                    format!("too many requests, the limit is {} per second", rate).into(),
+                ));
+                response.headers_mut().insert(RETRY_AFTER, HeaderValue::from_static("1"));
+                response
+            }
+        }
+    }
+}
+
+impl Limits {
+    /// Checks the limits, so a broken configuration is found on start.
+    pub fn init(&self) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        if self.requests_per_second == Some(0) {
+            return Err("requests_per_second must be greater than 0".into());
+        }
+
+        Ok(())
+    }
+
+    /// Takes a request of the peer from its bucket.
+    ///
+    /// Returns `Exceeded::Rate` if the bucket is empty.
+    pub fn throttle(&self, peer: Peer) -> Result<(), Exceeded> {
+        let rate = match self.requests_per_second {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+
+        let burst = f64::from(self.burst.unwrap_or(rate).max(1));
+        let per_second = f64::from(rate);
+        if !self.buckets.lock().unwrap().take(peer.into(), burst, per_second, Instant::now()) {
+            return Err(Exceeded::Rate(rate));
+        }
+
+        Ok(())
+    }
+
+    /// Checks the size of the body of a request and, if it is a `write`, the length of its keys
+    /// and the size of its values.
+    ///
+    /// The body is read only if it has to be checked, and is then put back into the request.
+    pub async fn check(&self, req: Request<Body>, write: bool) -> Result<Result<Request<Body>, Exceeded>, hyper::Error> {
+        let key = path::key(&req).filter(|key| !key.is_empty());
+        if let (true, Some(key), Some(max)) = (write, &key, self.max_key_length) {
+            if key.len() > max {
+                return Ok(Err(Exceeded::Key(max)));
+            }
+        }
+
+        let entries = write && (self.max_value_size.is_some() || (key.is_none() && self.max_key_length.is_some()));
+        if self.max_body.is_none() && !entries {
+            return Ok(Ok(req));
+        }
+
+        let length = req.headers().get(CONTENT_LENGTH)
+            .and_then(|length| length.to_str().ok())
+            .and_then(|length| length.parse::<usize>().ok());
+        if let (Some(length), Some(max)) = (length, self.max_body) {
+            if length > max {
+                return Ok(Err(Exceeded::Body(max)));
+            }
+        }
+
+        let batch = key.is_none() && req.method() == Method::PUT;
+        let content_type = format::content_type(&req);
+        let (parts, mut body) = req.into_parts();
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.next().await.transpose()? {
+            buffer.extend_from_slice(&chunk);
+            if let Some(max) = self.max_body.filter(|max| buffer.len() > *max) {
+                return Ok(Err(Exceeded::Body(max)));
+            }
+        }
+
+        if entries {
+            let exceeded = match key {
+                Some(key) => self.max_value_size.filter(|max| buffer.len() > *max).map(|max| Exceeded::Value(key, max)),
+                None if batch => self.check_batch(&buffer, content_type),
+                None => None,
+            };
+
+            if let Some(exceeded) = exceeded {
+                return Ok(Err(exceeded));
+            }
+        }
+
+        Ok(Ok(Request::from_parts(parts, buffer.into())))
+    }
+
+    /// Returns the maximum length in bytes of a line of an import.
+    pub fn max_line(&self) -> Option<usize> {
+        self.max_body
+    }
+
+    /// Checks a line of an import: its length, the length of the key of its record and the size
+    /// of its value.
+    ///
+    /// A record that cannot be read is left for the state to reject.
+    pub fn check_line(&self, line: &[u8]) -> Option<Exceeded> {
+        if let Some(max) = self.max_body.filter(|max| line.len() > *max) {
+            return Some(Exceeded::Line(max));
+        }
+
+        if self.max_key_length.is_none() && self.max_value_size.is_none() {
+            return None;
+        }
+
+        let record = serde_json::from_slice::<serde_json::Value>(line).ok()?;
+        let key = record["key"].as_str()?;
+        if let Some(max) = self.max_key_length.filter(|max| key.len() > *max) {
+            return Some(Exceeded::Key(max));
+        }
+
+        self.max_value_size
+            .filter(|max| record["value"].to_string().len() > *max)
+            .map(|max| Exceeded::Value(key.to_string(), max))
+    }
+
+    /// Checks the keys and the values of a batch.
+    ///
+    /// A batch that cannot be read is left for the state to reject.
+    fn check_batch(&self, body: &[u8], content_type: state::Format) -> Option<Exceeded> {
+        let batch = content_type
+            .decode(body)
+            .ok()
+            .and_then(|body| serde_json::from_slice::<BTreeMap<String, serde_json::Value>>(&body).ok())?;
+
+        batch.into_iter().find_map(|(key, value)| {
+            if let Some(max) = self.max_key_length.filter(|max| key.len() > *max) {
+                return Some(Exceeded::Key(max));
+            }
+
+            self.max_value_size
+                .filter(|max| value.to_string().len() > *max)
+                .map(|max| Exceeded::Value(key, max))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn limits(yaml: &str) -> Limits {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn tcp(addr: &str) -> Peer {
+        Peer::Tcp(addr.parse().unwrap())
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn should_throttle_clients_over_the_rate() {
+        let limits = limits("{requests_per_second: 1, burst: 2}");
+
+        assert_eq!(limits.throttle(tcp("10.0.0.1:4242")), Ok(()));
+        assert_eq!(limits.throttle(tcp("10.0.0.1:4243")), Ok(()));
+        assert_eq!(limits.throttle(tcp("10.0.0.1:4242")), Err(Exceeded::Rate(1)));
+        assert_eq!(limits.throttle(tcp("10.0.0.2:4242")), Ok(()));
+    }
+
+    #[test]
+    fn should_tell_ipv6_clients_apart_by_their_prefix() {
+        let client = |addr: &str| Client::from(tcp(addr));
+
+        assert_eq!(client("[2001:db8:0:1::1]:4242"), client("[2001:db8:0:1:ffff::2]:4242"));
+        assert_ne!(client("[2001:db8:0:1::1]:4242"), client("[2001:db8:0:2::1]:4242"));
+        assert_eq!(client("[::ffff:10.0.0.1]:4242"), client("10.0.0.1:4242"));
+        assert_ne!(client("[::ffff:10.0.0.1]:4242"), client("[::ffff:10.0.0.2]:4242"));
+
+        // IPv4-compatible addresses are plain IPv6 addresses
+        assert_eq!(client("[::1]:4242"), Client::Ip(ip("::")));
+        assert_ne!(client("[::1]:4242"), client("0.0.0.1:4242"));
+        assert_eq!(client("[::10.0.0.1]:4242"), Client::Ip(ip("::")));
+        assert_ne!(client("[::10.0.0.1]:4242"), client("10.0.0.1:4242"));
+    }
+
+    #[test]
+    fn should_reject_a_rate_of_zero() {
+        assert!(limits("{requests_per_second: 0}").init().is_err());
+        assert!(limits("{requests_per_second: 1, burst: 0}").init().is_ok());
+        assert!(limits("{}").init().is_ok());
+    }
+
+    #[test]
+    fn should_forget_the_clients_that_were_idle_the_longest() {
+        let mut buckets = Buckets::default();
+        let start = Instant::now();
+        let client = |i: u32| Client::Uid(Some(i));
+
+        assert!(buckets.take(client(0), 1.0, 0.0, start));
+        for i in 1..=MAX_CLIENTS as u32 {
+            assert!(buckets.take(client(i), 1.0, 0.0, start + Duration::from_millis(u64::from(i))));
+        }
+
+        assert_eq!(buckets.buckets.len(), MAX_CLIENTS);
+        assert_eq!(buckets.used.len(), MAX_CLIENTS);
+
+        // the first client was forgotten, so it starts with a full bucket again
+        let now = start + Duration::from_secs(60);
+        assert!(buckets.take(client(0), 1.0, 0.0, now));
+        assert!(!buckets.take(client(MAX_CLIENTS as u32), 1.0, 0.0, now));
+        assert_eq!(buckets.buckets.len(), MAX_CLIENTS);
+    }
+
+    #[test]
+    fn should_check_the_lines_of_an_import() {
+        let limits = limits("{max_body: 64, max_key_length: 5, max_value_size: 8}");
+
+        assert_eq!(limits.check_line(br#"{"key":"cat","value":"tom"}"#), None);
+        assert_eq!(limits.check_line(b"not a record"), None);
+        assert_eq!(limits.check_line(&[b' '; 65]), Some(Exceeded::Line(64)));
+        assert_eq!(limits.check_line(br#"{"key":"garfield","value":1}"#), Some(Exceeded::Key(5)));
+        assert_eq!(
+            limits.check_line(br#"{"key":"cat","value":"garfield"}"#),
+            Some(Exceeded::Value("cat".to_string(), 8))
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! The peer on the other side of a connection to the agent.
+
+use hyper::server::conn::AddrStream;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use tokio::net::UnixStream;
+
+/// The peer of a connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Peer {
+    /// A peer that is connected over TCP, with its address.
+    Tcp(SocketAddr),
+
+    /// A peer that is connected over the Unix domain socket, with its credentials if they could
+    /// be read.
+    Unix(Option<Credentials>),
+}
+
+/// The credentials of the process on the other side of a Unix domain socket.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Matches Unix domain socket peers by their credentials.
+///
+/// A peer matches if its uid and its gid match the ones that are set. A match that sets neither
+/// would match every Unix domain socket peer whose credentials could be read, so the rules that
+/// use it refuse to start with one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Match {
+    #[serde(default)]
+    uid: Option<u32>,
+
+    #[serde(default)]
+    gid: Option<u32>,
+}
+
+impl Match {
+    /// Returns true if neither a uid nor a gid is set.
+    pub fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none()
+    }
+
+    pub fn matches(&self, peer: Peer) -> bool {
+        match peer {
+            Peer::Unix(Some(credentials)) => {
+                self.uid.unwrap_or(credentials.uid) == credentials.uid
+                    && self.gid.unwrap_or(credentials.gid) == credentials.gid
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A range of IP addresses in CIDR notation, like `10.0.0.0/8` or `fd00::/8`.
+///
+/// A single address stands for a range of its own. IPv4 ranges also match IPv4-mapped IPv6
+/// addresses.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) if self.addr.is_ipv4() && is_mapped(v6.segments()) => v6.to_ipv4().map_or(addr, IpAddr::V4),
+            _ => addr,
+        };
+
+        match (self.addr, addr) {
+            (IpAddr::V4(range), IpAddr::V4(addr)) => masked(u32::from(range).into(), u32::from(addr).into(), self.len, 32),
+            (IpAddr::V6(range), IpAddr::V6(addr)) => masked(u128::from(range), u128::from(addr), self.len, 128),
+            _ => false,
+        }
+    }
+}
+
+/// Returns true if the segments belong to an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`).
+pub fn is_mapped(segments: [u16; 8]) -> bool {
+    segments[..5] == [0; 5] && segments[5] == 0xffff
+}
+
+/// Returns true if the first `len` of the `bits` bits of `range` and `addr` are equal.
+fn masked(range: u128, addr: u128, len: u8, bits: u8) -> bool {
+    let shift = u32::from(bits - len);
+    range.checked_shr(shift).unwrap_or(0) == addr.checked_shr(shift).unwrap_or(0)
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid CIDR '{}'", value);
+        let (addr, len) = match value.find('/') {
+            Some(slash) => (&value[..slash], Some(&value[slash + 1..])),
+            None => (value.as_str(), None),
+        };
+
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.parse().ok().filter(|len| *len <= bits).ok_or_else(invalid)?,
+            None => bits,
+        };
+
+        Ok(Cidr { addr, len })
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> Self {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// A connection that knows its peer.
+pub trait Connection {
+    fn peer(&self) -> Peer;
+}
+
+impl Connection for AddrStream {
+    fn peer(&self) -> Peer {
+        Peer::Tcp(self.remote_addr())
+    }
+}
+
+impl Connection for UnixStream {
+    /// Reads the credentials of the peer process from the socket (`SO_PEERCRED`).
+    fn peer(&self) -> Peer {
+        Peer::Unix(self.peer_cred().ok().map(|cred| Credentials { uid: cred.uid, gid: cred.gid }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(value: &str) -> Result<Cidr, String> {
+        Cidr::try_from(value.to_string())
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn unix(uid: u32, gid: u32) -> Peer {
+        Peer::Unix(Some(Credentials { uid, gid }))
+    }
+
+    #[test]
+    fn should_parse_cidrs() {
+        assert_eq!(cidr("10.0.0.0/8").map(String::from), Ok("10.0.0.0/8".to_string()));
+        assert_eq!(cidr("10.1.2.3").map(String::from), Ok("10.1.2.3/32".to_string()));
+        assert_eq!(cidr("fd00::/8").map(String::from), Ok("fd00::/8".to_string()));
+        assert_eq!(cidr("::1").map(String::from), Ok("::1/128".to_string()));
+        assert_eq!(cidr("0.0.0.0/0").map(String::from), Ok("0.0.0.0/0".to_string()));
+
+        assert!(cidr("10.0.0.0/").is_err());
+        assert!(cidr("10.0.0.0/33").is_err());
+        assert!(cidr("fd00::/129").is_err());
+        assert!(cidr("10.0.0.0/-1").is_err());
+        assert!(cidr("10.0.0/8").is_err());
+        assert!(cidr("garfield").is_err());
+    }
+
+    #[test]
+    fn should_contain_addresses_in_range() {
+        let range = cidr("10.1.0.0/16").unwrap();
+        assert!(range.contains(ip("10.1.0.0")));
+        assert!(range.contains(ip("10.1.255.255")));
+        assert!(!range.contains(ip("10.2.0.0")));
+        assert!(!range.contains(ip("fd00::1")));
+
+        let all = cidr("0.0.0.0/0").unwrap();
+        assert!(all.contains(ip("255.255.255.255")));
+        assert!(!all.contains(ip("::1")));
+
+        let single = cidr("127.0.0.1/32").unwrap();
+        assert!(single.contains(ip("127.0.0.1")));
+        assert!(!single.contains(ip("127.0.0.2")));
+
+        let v6 = cidr("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(cidr("::/0").unwrap().contains(ip("::1")));
+    }
+
+    #[test]
+    fn should_match_ipv4_mapped_addresses() {
+        assert!(cidr("127.0.0.1").unwrap().contains(ip("::ffff:127.0.0.1")));
+        assert!(!cidr("127.0.0.1").unwrap().contains(ip("::ffff:127.0.0.2")));
+        assert!(!cidr("::1").unwrap().contains(ip("::ffff:0.0.0.1")));
+    }
+
+    #[test]
+    fn should_match_credentials() {
+        let uid: Match = serde_yaml::from_str("uid: 1000").unwrap();
+        assert!(uid.matches(unix(1000, 1)));
+        assert!(!uid.matches(unix(1001, 1)));
+
+        let both: Match = serde_yaml::from_str("{uid: 1000, gid: 100}").unwrap();
+        assert!(both.matches(unix(1000, 100)));
+        assert!(!both.matches(unix(1000, 101)));
+        assert!(!both.matches(unix(1001, 100)));
+
+        assert!(!uid.matches(Peer::Unix(None)));
+        assert!(!uid.matches(Peer::Tcp("127.0.0.1:4242".parse().unwrap())));
+        assert!(Match::default().is_empty());
+        assert!(!uid.is_empty());
+    }
+}
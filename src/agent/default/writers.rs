@@ -0,0 +1,104 @@
+//! Restricts the clients that can write through the agent.
+//!
+//! By default, any client that can reach the agent (and is authenticated, when [auth] is
+//! configured) can set, patch, delete and import values. An agent can be turned read-only with
+//! `read_only`, or writes can be allowed only from the clients that match `writers`:
+//!
+//! - `cidrs` - TCP clients whose source address is in one of the ranges, like `10.0.0.0/8`,
+//!   `fd00::/8` or a single address.
+//! - `peers` - Unix domain socket clients whose process matches a `uid`, a `gid` or both. Like the
+//!   `peers` rules of [auth], a rule must set at least one of them.
+//!
+//! ```yaml
+//! agent:
+//!   kind: Default
+//!   writers:
+//!     cidrs: ["127.0.0.1/32", "10.1.0.0/16"]
+//!     peers:
+//!       - uid: 1000
+//! ```
+//!
+//! A blocked write is rejected with `403` (forbidden). Reads are not affected, and neither is the
+//! replication between the peers, which goes through the [connection] layer.
+//!
+//! [auth]: super::auth
+//! [connection]: crate::connection
+
+use super::peer::{Cidr, Match, Peer};
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+
+/// The clients that are allowed to write.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Writers {
+    cidrs: Vec<Cidr>,
+    peers: Vec<Match>,
+}
+
+impl Writers {
+    /// Checks the rules, so a broken configuration is found on start.
+    pub fn init(&self) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        if self.peers.iter().any(Match::is_empty) {
+            return Err("a writers peers rule must set a uid, a gid or both".into());
+        }
+
+        Ok(())
+    }
+
+    /// Returns true if the peer is allowed to write.
+    pub fn allows(&self, peer: Peer) -> bool {
+        match peer {
+            Peer::Tcp(addr) => self.cidrs.iter().any(|cidr| cidr.contains(addr.ip())),
+            Peer::Unix(_) => self.peers.iter().any(|rule| rule.matches(peer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::peer::Credentials;
+    use super::*;
+
+    fn writers(yaml: &str) -> Writers {
+        let writers: Writers = serde_yaml::from_str(yaml).unwrap();
+        writers.init().unwrap();
+        writers
+    }
+
+    fn tcp(addr: &str) -> Peer {
+        Peer::Tcp(addr.parse().unwrap())
+    }
+
+    fn unix(uid: u32, gid: u32) -> Peer {
+        Peer::Unix(Some(Credentials { uid, gid }))
+    }
+
+    #[test]
+    fn should_allow_writes_from_matching_clients() {
+        let writers = writers(r#"{cidrs: ["127.0.0.1/32", "10.1.0.0/16"], peers: [{uid: 1000}, {gid: 100}]}"#);
+
+        assert!(writers.allows(tcp("127.0.0.1:4242")));
+        assert!(writers.allows(tcp("10.1.2.3:4242")));
+        assert!(writers.allows(tcp("[::ffff:10.1.2.3]:4242")));
+        assert!(!writers.allows(tcp("10.2.0.1:4242")));
+
+        assert!(writers.allows(unix(1000, 1000)));
+        assert!(writers.allows(unix(1001, 100)));
+        assert!(!writers.allows(unix(1001, 1001)));
+        assert!(!writers.allows(Peer::Unix(None)));
+    }
+
+    #[test]
+    fn should_block_all_writes_without_rules() {
+        let writers = writers("{}");
+        assert!(!writers.allows(tcp("127.0.0.1:4242")));
+        assert!(!writers.allows(unix(0, 0)));
+    }
+
+    #[test]
+    fn should_reject_peers_rules_without_credentials() {
+        let writers: Writers = serde_yaml::from_str("peers: [{}]").unwrap();
+        assert!(writers.init().is_err());
+    }
+}
@@ -0,0 +1,492 @@
+//! A GraphQL implementation of the Agent trait.
+//!
+//! This agent allows an app to select and shape many values in a single round trip. Values are
+//! JSON, so they are exposed as a `JSON` scalar, and parts of a value can be selected with JSON
+//! pointers. This agent expects a state that serializes its values to JSON, like the [Default]
+//! state.
+//!
+//! # Configuration
+//!
+//! ```yaml
+//! agent:
+//!   kind: GraphQL
+//!   port: 3097
+//! ```
+//!
+//! # Schema
+//!
+//! ```graphql
+//! type Query {
+//!   value(key: String!): Entry
+//!   values(prefix: String! = "", first: Int, after: String): EntryConnection!
+//! }
+//!
+//! type Subscription {
+//!   changes(prefix: String! = ""): Change!
+//! }
+//!
+//! type Entry {
+//!   key: String!
+//!   value: JSON!
+//!   field(path: String!): JSON
+//!   ts: Int
+//!   ttl: Int
+//! }
+//!
+//! type Change {
+//!   kind: ChangeKind!
+//!   key: String!
+//!   ts: Int!
+//!   entry: Entry
+//! }
+//! ```
+//!
+//! `values` is a Relay-style connection. Keys are listed in lexicographical order and the key of
+//! each edge is its cursor. `field` selects a part of the value by a JSON pointer and returns
+//! null if it does not exist. Aliases can be used to shape the result:
+//!
+//! ```graphql
+//! {
+//!   cat: value(key: "cat") { name: field(path: "/name") city: field(path: "/address/city") }
+//!   values(prefix: "dogs/", first: 10) {
+//!     edges { cursor node { key value } }
+//!     pageInfo { hasNextPage endCursor }
+//!   }
+//! }
+//! ```
+//!
+//! Timestamps and TTLs are in milliseconds, which do not fit the 32 bits of a GraphQL `Int`, so
+//! clients should read them as 64-bit integers.
+//!
+//! # Transport
+//! Queries are sent as a `POST` request with a JSON body (`{"query", "variables",
+//! "operationName"}`, or an array of those for a batch) or as a `GET` request with the same fields
+//! in the query string.
+//!
+//! Subscriptions are served over WebSocket, using either the `graphql-transport-ws` or the
+//! `graphql-ws` protocol, as requested by the client in the `Sec-WebSocket-Protocol` header. A
+//! subscription fails with an error if the client falls too far behind the changes, in which
+//! case it should read the values again and resubscribe.
+//!
+//! [Default]: crate::state::default
+
+use crate::agent;
+use crate::helpers::http::responses::Responses;
+use crate::state::{self, Change, ChangeKind, StateValue};
+use async_graphql::connection::{Connection, Edge};
+use async_graphql::http::{WebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::{Context, EmptyMutation, Enum, Error, Json, Object, Schema, Subscription};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::{SinkExt, Stream, StreamExt};
+use http::{Request, Response, StatusCode};
+use hyper::header::{HeaderValue, CONTENT_TYPE, SEC_WEBSOCKET_PROTOCOL};
+use hyper::{http::Method, service::make_service_fn, service::service_fn, Body, Server};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+use tokio::sync::broadcast::{self, RecvError};
+use tokio_tungstenite::tungstenite::handshake::server::create_response;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame, Role};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+type Result<T> = std::result::Result<T, Box<dyn StdError + Send + Sync>>;
+
+type AgentSchema = Schema<Query, EmptyMutation, Subscription>;
+
+/// The GraphQL struct.
+///
+/// This struct holds information loaded from the agent configuration.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct GraphQL {
+    /// Binds and accepts connections on this port.
+    /// Default port: 3097
+    port: u16,
+}
+
+/// Default values for this implementation.
+impl std::default::Default for GraphQL {
+    fn default() -> Self {
+        GraphQL { port: 3097 }
+    }
+}
+
+/// A key and its value.
+///
+/// The value is expected to serialize to a JSON object with `value`, `ts` and `ttl` fields, like
+/// the values of the [Default](crate::state::default) state.
+struct Entry {
+    key: String,
+    value: serde_json::Value,
+}
+
+impl Entry {
+    fn new(key: String, value: &dyn StateValue) -> Option<Entry> {
+        let value = serde_json::from_slice(&value.as_bytes()?).ok()?;
+        Some(Entry { key, value })
+    }
+}
+
+#[Object]
+impl Entry {
+    async fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// The value itself.
+    async fn value(&self) -> Json<&serde_json::Value> {
+        Json(&self.value["value"])
+    }
+
+    /// Selects a part of the value by a JSON pointer, for example `/address/city`.
+    async fn field(&self, path: String) -> Option<Json<&serde_json::Value>> {
+        self.value["value"].pointer(&path).map(Json)
+    }
+
+    /// The timestamp of the value in milliseconds since the epoch.
+    async fn ts(&self) -> Option<u64> {
+        self.value["ts"].as_u64()
+    }
+
+    /// The TTL of the value in milliseconds.
+    async fn ttl(&self) -> Option<u64> {
+        self.value["ttl"].as_u64()
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[graphql(name = "ChangeKind")]
+enum Kind {
+    Set,
+    Delete,
+    Expire,
+}
+
+/// A change of a key.
+struct ChangeEvent(Change);
+
+#[Object(name = "Change")]
+impl ChangeEvent {
+    async fn kind(&self) -> Kind {
+        match self.0.kind {
+            ChangeKind::Set => Kind::Set,
+            ChangeKind::Delete => Kind::Delete,
+            ChangeKind::Expire => Kind::Expire,
+        }
+    }
+
+    async fn key(&self) -> &str {
+        &self.0.key
+    }
+
+    /// The timestamp of the change.
+    async fn ts(&self) -> u64 {
+        self.0.ts
+    }
+
+    /// The new entry. Only set for `SET` changes.
+    async fn entry(&self) -> Option<Entry> {
+        self.0.value.as_ref().and_then(|value| Entry::new(self.0.key.clone(), &**value))
+    }
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    /// Returns the entry of a key or null if the key does not exist.
+    async fn value(&self, ctx: &Context<'_>, key: String) -> Option<Entry> {
+        let state = ctx.data_unchecked::<state::SafeState>();
+        state.get(&key as &dyn StateValue).and_then(|value| Entry::new(key, &*value))
+    }
+
+    /// Lists the entries of the keys that start with a prefix, in lexicographical order.
+    async fn values(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] prefix: String,
+        first: Option<usize>,
+        after: Option<String>,
+    ) -> async_graphql::Result<Connection<String, Entry>> {
+        if first == Some(0) {
+            return Err(Error::new("'first' must be greater than 0"));
+        }
+
+        let state = ctx.data_unchecked::<state::SafeState>();
+        let scan = state::Scan { prefix, limit: first, cursor: after.clone(), values: true, within: None };
+        let page = state.scan(&scan).map_err(|e| Error::new(e.to_string()))?;
+        let page: serde_json::Value = serde_json::from_slice(&page.as_bytes().unwrap_or_default())?;
+
+        let mut connection = Connection::new(after.is_some(), !page["next"].is_null());
+        if let Some(values) = page["values"].as_object() {
+            connection.edges.extend(
+                values
+                    .iter()
+                    .map(|(key, value)| Edge::new(key.clone(), Entry { key: key.clone(), value: value.clone() })),
+            );
+        }
+
+        Ok(connection)
+    }
+}
+
+struct Subscription;
+
+#[Subscription]
+impl Subscription {
+    /// Streams the changes of all keys that start with a prefix.
+    async fn changes(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] prefix: String,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<ChangeEvent>>> {
+        let changes = ctx
+            .data_unchecked::<state::SafeState>()
+            .subscribe()
+            .ok_or("the state does not support change notification")?;
+
+        Ok(futures::stream::unfold(Some(changes), move |changes| {
+            let prefix = prefix.clone();
+            next(changes, prefix)
+        }))
+    }
+}
+
+/// Receives the next change of a key that starts with the prefix.
+///
+/// Ends the stream with an error if the receiver lagged behind the changes.
+async fn next(
+    changes: Option<broadcast::Receiver<Change>>,
+    prefix: String,
+) -> Option<(async_graphql::Result<ChangeEvent>, Option<broadcast::Receiver<Change>>)> {
+    let mut changes = changes?;
+    loop {
+        match changes.recv().await {
+            Ok(change) if change.key.starts_with(&prefix) => return Some((Ok(ChangeEvent(change)), Some(changes))),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(missed)) => return Some((Err(format!("missed {} changes", missed).into()), None)),
+            Err(RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Executes a query sent as a `GET` or a `POST` request.
+async fn query_handler(schema: AgentSchema, req: Request<Body>) -> Result<Response<Body>> {
+    let batch = match *req.method() {
+        Method::GET => async_graphql::http::parse_query_string(req.uri().query().unwrap_or_default())
+            .map(async_graphql::BatchRequest::Single)
+            .map_err(|e| e.to_string()),
+        _ => {
+            let body = hyper::body::to_bytes(req.into_body()).await?;
+            serde_json::from_slice::<async_graphql::BatchRequest>(&body).map_err(|e| e.to_string())
+        }
+    };
+
+    let batch = match batch {
+        Ok(batch) => batch,
+        Err(e) => return Ok(Responses::bad_request(Some(e.into()))),
+    };
+
+    let mut response = Responses::ok(serde_json::to_vec(&schema.execute_batch(batch).await)?.into());
+    response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+    Ok(response)
+}
+
+/// Upgrades the request to a WebSocket that serves subscriptions.
+fn subscription_handler(schema: AgentSchema, req: Request<Body>) -> Result<Response<Body>> {
+    let protocol = req
+        .headers()
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|header| header.to_str().ok())
+        .and_then(|header| header.split(',').find_map(|p| p.trim().parse::<WebSocketProtocols>().ok()));
+
+    let protocol = match protocol {
+        Some(protocol) => protocol,
+        None => return Ok(Responses::bad_request(Some("expected a graphql-transport-ws or graphql-ws protocol".into()))),
+    };
+
+    let mut handshake = Request::new(());
+    *handshake.method_mut() = req.method().clone();
+    *handshake.version_mut() = req.version();
+    *handshake.headers_mut() = req.headers().clone();
+
+    let mut response = match create_response(&handshake) {
+        Ok(response) => response.map(|_| Body::empty()),
+        Err(e) => return Ok(Responses::bad_request(Some(e.to_string().into()))),
+    };
+    response
+        .headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static(protocol.sec_websocket_protocol()));
+
+    tokio::spawn(async move {
+        match req.into_body().on_upgrade().await {
+            Ok(upgraded) => {
+                let ws = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                if let Err(e) = subscriptions(schema, ws, protocol).await {
+                    warn!("GraphQL WebSocket failed; {}", e);
+                }
+            }
+            Err(e) => warn!("Failed to upgrade to a GraphQL WebSocket; {}", e),
+        }
+    });
+
+    Ok(response)
+}
+
+/// Serves the subscriptions of a single WebSocket until it is closed.
+async fn subscriptions<S>(schema: AgentSchema, ws: WebSocketStream<S>, protocol: WebSocketProtocols) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut sink, stream) = ws.split();
+    let stream = stream
+        .take_while(|message| future::ready(message.is_ok()))
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Text(text)) => Some(text.into_bytes()),
+                Ok(Message::Binary(bytes)) => Some(bytes),
+                _ => None,
+            })
+        });
+
+    let mut messages = WebSocket::new(schema, stream, protocol);
+    while let Some(message) = messages.next().await {
+        match message {
+            WsMessage::Text(text) => sink.send(Message::Text(text)).await?,
+            WsMessage::Close(code, reason) => {
+                let frame = CloseFrame { code: CloseCode::from(code), reason: reason.into() };
+                sink.send(Message::Close(Some(frame))).await?;
+                break;
+            }
+        }
+    }
+
+    debug!("GraphQL WebSocket closed");
+    Ok(())
+}
+
+async fn handler(schema: AgentSchema, req: Request<Body>) -> Result<Response<Body>> {
+    let upgrade = req.headers().contains_key(hyper::header::UPGRADE);
+    match (req.method(), upgrade) {
+        (&Method::GET, true) => subscription_handler(schema, req),
+        (&Method::GET, false) | (&Method::POST, _) => query_handler(schema, req).await,
+        _ => Ok(Responses::response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed".into())),
+    }
+}
+
+impl GraphQL {
+    async fn server(&self, state: state::SafeState) -> Result<()> {
+        let schema = Schema::build(Query, EmptyMutation, Subscription).data(state).finish();
+        let service = make_service_fn(move |_| {
+            let schema = schema.clone();
+            async move {
+                Ok::<_, Box<dyn StdError + Send + Sync>>(service_fn(move |req| handler(schema.clone(), req)))
+            }
+        });
+
+        Server::try_bind(&([0, 0, 0, 0], self.port).into())?.serve(service).await?;
+
+        Ok(())
+    }
+}
+
+#[typetag::serde]
+impl agent::Agent for GraphQL {
+    /// Starts the server while passing the current state to be used by the resolvers.
+    fn start<'a>(&'a self, state: state::SafeState) -> BoxFuture<'a, Result<()>> {
+        self.server(state).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn schema() -> AgentSchema {
+        let state: state::SafeState = Arc::new(state::default::Default::default());
+        let values = [
+            ("cats/garfield", r#"{"name":"Garfield","address":{"city":"Muncie"}}"#),
+            ("cats/tom", r#""Tom""#),
+            ("dog", r#""Odie""#),
+        ];
+        for (key, value) in &values {
+            state.insert_if(&key.to_string(), value, Some(60000), state::Condition::Missing).unwrap();
+        }
+
+        Schema::build(Query, EmptyMutation, Subscription).data(state).finish()
+    }
+
+    async fn execute(schema: &AgentSchema, query: &str) -> (serde_json::Value, Vec<String>) {
+        let response = schema.execute(query).await;
+        let errors = response.errors.iter().map(|e| e.message.clone()).collect();
+        (response.data.into_json().unwrap(), errors)
+    }
+
+    #[tokio::test]
+    async fn should_resolve_values_and_fields() {
+        let schema = schema();
+        let (data, errors) = execute(
+            &schema,
+            r#"{
+                cat: value(key: "cats/garfield") {
+                    key
+                    name: field(path: "/name")
+                    city: field(path: "/address/city")
+                    age: field(path: "/age")
+                    ttl
+                }
+                dog: value(key: "dog") { value }
+                mouse: value(key: "mouse") { key }
+            }"#,
+        )
+        .await;
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(
+            data,
+            json!({
+                "cat": {"key": "cats/garfield", "name": "Garfield", "city": "Muncie", "age": null, "ttl": 60000},
+                "dog": {"value": "Odie"},
+                "mouse": null,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn should_page_through_values() {
+        let schema = schema();
+        let query = |after: &str| {
+            format!(
+                r#"{{ values(prefix: "cats/", first: 1{}) {{
+                    edges {{ cursor node {{ key }} }}
+                    pageInfo {{ hasNextPage endCursor }}
+                }} }}"#,
+                after
+            )
+        };
+
+        let (data, _) = execute(&schema, &query("")).await;
+        assert_eq!(data["values"]["edges"], json!([{"cursor": "cats/garfield", "node": {"key": "cats/garfield"}}]));
+        assert_eq!(
+            data["values"]["pageInfo"],
+            json!({"hasNextPage": true, "endCursor": "cats/garfield"})
+        );
+
+        let (data, _) = execute(&schema, &query(r#", after: "cats/garfield""#)).await;
+        assert_eq!(data["values"]["edges"], json!([{"cursor": "cats/tom", "node": {"key": "cats/tom"}}]));
+        assert_eq!(data["values"]["pageInfo"]["hasNextPage"], json!(false));
+
+        let (data, _) = execute(&schema, r#"{ values { edges { cursor } } }"#).await;
+        assert_eq!(data["values"]["edges"].as_array().map(Vec::len), Some(3));
+    }
+
+    #[tokio::test]
+    async fn should_reject_an_empty_page() {
+        let (_, errors) = execute(&schema(), r#"{ values(first: 0) { edges { cursor } } }"#).await;
+        assert_eq!(errors, vec!["'first' must be greater than 0"]);
+    }
+}
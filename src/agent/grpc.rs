@@ -0,0 +1,384 @@
+//! A gRPC implementation of the Agent trait.
+//!
+//! This agent exposes the state as a gRPC service, so apps can generate typed clients from the
+//! published `proto/c19.proto` instead of building the JSON envelope the [default agent] expects.
+//!
+//! Values are passed as JSON text, so this agent expects a state that serializes its values to
+//! JSON, like the [Default] state.
+//!
+//! # Configuration
+//!
+//! ```yaml
+//! agent:
+//!   kind: Grpc
+//!   port: 3097
+//! ```
+//!
+//! # RPCs
+//!
+//! - `Get` - returns the value of a key or fails with `NOT_FOUND`.
+//! - `Set` - sets the value of a key and returns its timestamp. When a condition is specified
+//!   (`if_exists`, `if_missing` or `if_ts`), the check and the write are done by the state at once
+//!   and the call fails with `FAILED_PRECONDITION` if the condition did not hold.
+//! - `Delete` - deletes a key.
+//! - `BatchGet` - returns the values of a batch of keys from a single snapshot of the state.
+//! - `Watch` - streams the changes of all keys that start with a prefix. The stream fails with
+//!   `DATA_LOSS` if the client falls too far behind the changes.
+//!
+//! `Set` and `Delete` are committed to the local state before they return, so a `Get` right after
+//! them always sees the write.
+//!
+//! [Default]: crate::state::default
+//! [default agent]: crate::agent::default
+
+use crate::agent;
+use crate::state::{self, Change, ChangeKind, Condition, StateValue};
+use futures::future::{BoxFuture, FutureExt};
+use futures::stream::Stream;
+use log::debug;
+use proto::set_request;
+use proto::state_server::{State, StateServer};
+use proto::watch_event::Kind;
+use proto::{
+    BatchGetRequest, BatchGetResponse, DeleteRequest, DeleteResponse, Entry, GetRequest, GetResponse,
+    SetRequest, SetResponse, WatchEvent, WatchRequest,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::broadcast::RecvError;
+use tokio::sync::{mpsc, oneshot};
+use tonic::{Request, Response, Status};
+
+/// The types and the service generated from `proto/c19.proto`.
+#[allow(clippy::enum_variant_names)]
+pub mod proto {
+    tonic::include_proto!("c19");
+}
+
+/// The maximum number of events that are buffered for a single watch.
+const MAX_WATCH_EVENTS: usize = 128;
+
+/// The Grpc struct.
+///
+/// This struct holds information loaded from the agent configuration.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Grpc {
+    /// Binds and accepts connections on this port.
+    /// Default port: 3097
+    port: u16,
+}
+
+/// Default values for this implementation.
+impl std::default::Default for Grpc {
+    fn default() -> Self {
+        Grpc { port: 3097 }
+    }
+}
+
+/// Builds an entry from a value of the state.
+///
+/// The value is expected to serialize to a JSON object with `value`, `ts` and `ttl` fields, like
+/// the values of the [Default](crate::state::default) state.
+fn entry(key: String, value: &serde_json::Value) -> Entry {
+    Entry {
+        key,
+        value: value["value"].to_string(),
+        ts: value["ts"].as_u64().unwrap_or_default(),
+        ttl: value["ttl"].as_u64().unwrap_or_default(),
+    }
+}
+
+fn parse(value: &dyn StateValue) -> Option<serde_json::Value> {
+    value.as_bytes().and_then(|value| serde_json::from_slice(&value).ok())
+}
+
+/// The gRPC service.
+struct Service {
+    state: state::SafeState,
+}
+
+#[tonic::async_trait]
+impl State for Service {
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        let key = request.into_inner().key;
+        let value = self
+            .state
+            .get(&key as &dyn StateValue)
+            .ok_or_else(|| Status::not_found(format!("{} not found", key)))?;
+
+        let value = parse(&*value).ok_or_else(|| Status::internal("failed to read the value"))?;
+
+        Ok(Response::new(GetResponse { entry: Some(entry(key, &value)) }))
+    }
+
+    async fn set(&self, request: Request<SetRequest>) -> Result<Response<SetResponse>, Status> {
+        let request = request.into_inner();
+        let ttl = Some(request.ttl).filter(|ttl| *ttl > 0);
+        let condition = match request.condition {
+            None => Condition::Always,
+            Some(set_request::Condition::IfExists(_)) => Condition::Exists,
+            Some(set_request::Condition::IfMissing(_)) => Condition::Missing,
+            Some(set_request::Condition::IfTs(ts)) => Condition::Ts(ts),
+        };
+
+        let (key, value) = (&request.key as &dyn StateValue, &request.value as &dyn StateValue);
+        match self.state.insert_if(key, value, ttl, condition) {
+            Ok(Some(ts)) => Ok(Response::new(SetResponse { ts })),
+            Ok(None) => Err(Status::failed_precondition("the condition did not hold")),
+            Err(e) => Err(Status::invalid_argument(e.to_string())),
+        }
+    }
+
+    async fn delete(&self, request: Request<DeleteRequest>) -> Result<Response<DeleteResponse>, Status> {
+        self.state
+            .delete_if(&request.into_inner().key as &dyn StateValue, Condition::Always)
+            .map(|_| Response::new(DeleteResponse {}))
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+
+    async fn batch_get(&self, request: Request<BatchGetRequest>) -> Result<Response<BatchGetResponse>, Status> {
+        let keys = request.into_inner().keys;
+        let batch = serde_json::to_vec(&keys)
+            .map_err(|e| e.into())
+            .and_then(|keys| self.state.get_many(&keys as &dyn StateValue))
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let batch = parse(&*batch).ok_or_else(|| Status::internal("failed to read the values"))?;
+
+        let mut response = BatchGetResponse::default();
+        for key in keys {
+            match batch["found"].get(&key) {
+                Some(value) => response.found.push(entry(key, value)),
+                None => response.missing.push(key),
+            }
+        }
+
+        Ok(Response::new(response))
+    }
+
+    type WatchStream = Watch;
+
+    /// Streams the changes of the keys that start with the requested prefix.
+    ///
+    /// The changes are forwarded to the stream by a task that ends once the client goes away (and
+    /// the stream is dropped) or falls too far behind the changes.
+    async fn watch(&self, request: Request<WatchRequest>) -> Result<Response<Self::WatchStream>, Status> {
+        let prefix = request.into_inner().prefix;
+        let mut changes = self
+            .state
+            .subscribe()
+            .ok_or_else(|| Status::unimplemented("the state does not support change notification"))?;
+
+        let (mut tx, rx) = mpsc::channel(MAX_WATCH_EVENTS);
+        let (mut watching, watched) = oneshot::channel();
+        tokio::spawn(async move {
+            loop {
+                let change = tokio::select! {
+                    change = changes.recv() => change,
+                    _ = watching.closed() => {
+                        debug!("Watch of prefix '{}' ended, the client went away", prefix);
+                        return;
+                    }
+                };
+
+                let event = match change {
+                    Ok(change) if change.key.starts_with(&prefix) => Ok(event(change)),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(missed)) => Err(Status::data_loss(format!("missed {} changes", missed))),
+                    Err(RecvError::Closed) => return,
+                };
+
+                let lagged = event.is_err();
+                if tx.send(event).await.is_err() || lagged {
+                    debug!("Watch of prefix '{}' ended", prefix);
+                    return;
+                }
+            }
+        });
+
+        Ok(Response::new(Watch { events: rx, _watched: watched }))
+    }
+}
+
+/// The stream of the events of a watch.
+///
+/// Dropping the stream ends the task that forwards the changes to it.
+pub struct Watch {
+    events: mpsc::Receiver<Result<WatchEvent, Status>>,
+    _watched: oneshot::Receiver<()>,
+}
+
+impl Stream for Watch {
+    type Item = Result<WatchEvent, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Builds the event of a change.
+fn event(change: Change) -> WatchEvent {
+    let kind = match change.kind {
+        ChangeKind::Set => Kind::Set,
+        ChangeKind::Delete => Kind::Delete,
+        ChangeKind::Expire => Kind::Expire,
+    };
+
+    let Change { key, ts, value, .. } = change;
+    let entry = value
+        .and_then(|value| parse(&*value))
+        .map(|value| entry(key.clone(), &value));
+
+    WatchEvent { kind: kind as i32, key, ts, entry }
+}
+
+impl Grpc {
+    async fn server(&self, state: state::SafeState) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        let addr = ([0, 0, 0, 0], self.port).into();
+
+        tonic::transport::Server::builder()
+            .add_service(StateServer::new(Service { state }))
+            .serve(addr)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[typetag::serde]
+impl agent::Agent for Grpc {
+    /// Starts the server while passing the current state to be used by the service.
+    fn start<'a>(
+        &'a self,
+        state: state::SafeState,
+    ) -> BoxFuture<'a, Result<(), Box<dyn StdError + Send + Sync>>> {
+        self.server(state).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tonic::Code;
+
+    fn service() -> Service {
+        Service { state: Arc::new(state::default::Default::default()) }
+    }
+
+    fn request(key: &str, value: &str, condition: Option<set_request::Condition>) -> Request<SetRequest> {
+        Request::new(SetRequest { key: key.to_string(), value: value.to_string(), ttl: 0, condition })
+    }
+
+    async fn get(service: &Service, key: &str) -> Result<Entry, Status> {
+        let response = service.get(Request::new(GetRequest { key: key.to_string() })).await?;
+        Ok(response.into_inner().entry.unwrap())
+    }
+
+    async fn set(
+        service: &Service,
+        key: &str,
+        value: &str,
+        condition: Option<set_request::Condition>,
+    ) -> Result<u64, Code> {
+        match service.set(request(key, value, condition)).await {
+            Ok(response) => Ok(response.into_inner().ts),
+            Err(status) => Err(status.code()),
+        }
+    }
+
+    #[test]
+    fn should_build_entries_and_events() {
+        let value = json!({"value": {"name": "garfield"}, "ts": 42, "ttl": null});
+        let expected = Entry { key: "cat".to_string(), value: r#"{"name":"garfield"}"#.to_string(), ts: 42, ttl: 0 };
+        assert_eq!(entry("cat".to_string(), &value), expected);
+
+        let value = json!({"value": "garfield", "ts": 42, "ttl": 1000});
+        assert_eq!(entry("cat".to_string(), &value).ttl, 1000);
+
+        let change = |kind, value: Option<&str>| Change {
+            id: 1,
+            kind,
+            key: "cat".to_string(),
+            ts: 42,
+            value: value.map(|value| Arc::new(value.to_string()) as Arc<dyn StateValue>),
+        };
+
+        let set = event(change(ChangeKind::Set, Some(r#"{"value":"garfield","ts":42,"ttl":null}"#)));
+        assert_eq!(set.kind, Kind::Set as i32);
+        assert_eq!((set.key.as_str(), set.ts), ("cat", 42));
+        assert_eq!(set.entry.unwrap().value, r#""garfield""#);
+
+        let delete = event(change(ChangeKind::Delete, None));
+        assert_eq!(delete.kind, Kind::Delete as i32);
+        assert_eq!(delete.entry, None);
+
+        assert_eq!(event(change(ChangeKind::Expire, None)).kind, Kind::Expire as i32);
+    }
+
+    #[tokio::test]
+    async fn should_read_its_own_writes() {
+        let service = service();
+
+        let ts = set(&service, "cat", r#""garfield""#, None).await.unwrap();
+        assert!(ts > 0);
+
+        let entry = get(&service, "cat").await.unwrap();
+        assert_eq!((entry.value.as_str(), entry.ts), (r#""garfield""#, ts));
+
+        let newer = set(&service, "cat", r#""tom""#, None).await.unwrap();
+        assert!(newer > ts);
+        assert_eq!(get(&service, "cat").await.unwrap().value, r#""tom""#);
+
+        service.delete(Request::new(DeleteRequest { key: "cat".to_string() })).await.unwrap();
+        assert_eq!(get(&service, "cat").await.unwrap_err().code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn should_set_values_only_if_the_condition_holds() {
+        let service = service();
+        let missing = || Some(set_request::Condition::IfMissing(true));
+        let exists = || Some(set_request::Condition::IfExists(true));
+
+        assert_eq!(set(&service, "cat", "1", exists()).await, Err(Code::FailedPrecondition));
+        let ts = set(&service, "cat", "1", missing()).await.unwrap();
+        assert_eq!(set(&service, "cat", "2", missing()).await, Err(Code::FailedPrecondition));
+        assert_eq!(get(&service, "cat").await.unwrap().value, "1");
+
+        let stale = Some(set_request::Condition::IfTs(ts - 1));
+        assert_eq!(set(&service, "cat", "2", stale).await, Err(Code::FailedPrecondition));
+        let ts = set(&service, "cat", "2", Some(set_request::Condition::IfTs(ts))).await.unwrap();
+        assert_eq!(get(&service, "cat").await.unwrap().ts, ts);
+
+        let ts = set(&service, "cat", "3", exists()).await.unwrap();
+        let entry = get(&service, "cat").await.unwrap();
+        assert_eq!((entry.value.as_str(), entry.ts), ("3", ts));
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_json() {
+        let service = service();
+
+        assert_eq!(set(&service, "cat", "garfield", None).await, Err(Code::InvalidArgument));
+        let missing = Some(set_request::Condition::IfMissing(true));
+        assert_eq!(set(&service, "cat", "{", missing).await, Err(Code::InvalidArgument));
+        assert_eq!(get(&service, "cat").await.unwrap_err().code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn should_get_batches_of_keys() {
+        let service = service();
+        set(&service, "cat", r#""garfield""#, None).await.unwrap();
+        set(&service, "dog", r#""odie""#, None).await.unwrap();
+
+        let keys = vec!["dog".to_string(), "mouse".to_string(), "cat".to_string()];
+        let batch = service.batch_get(Request::new(BatchGetRequest { keys })).await.unwrap().into_inner();
+
+        let found: Vec<_> = batch.found.iter().map(|entry| (entry.key.as_str(), entry.value.as_str())).collect();
+        assert_eq!(found, vec![("dog", r#""odie""#), ("cat", r#""garfield""#)]);
+        assert_eq!(batch.missing, vec!["mouse"]);
+    }
+}
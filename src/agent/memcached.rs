@@ -0,0 +1,709 @@
+//! A memcached implementation of the Agent trait.
+//!
+//! This agent speaks the memcached text protocol so services that only have a memcached client
+//! can read and write the state.
+//!
+//! # Configuration
+//!
+//! ```yaml
+//! agent:
+//!   kind: Memcached
+//!   port: 11211
+//! ```
+//!
+//! # Commands
+//! The following commands are supported:
+//!
+//! - `get <key>*` and `gets <key>*` - `gets` returns the timestamp of the value as its CAS unique.
+//! - `set`, `add`, `replace`, `append`, `prepend` - `<command> <key> <flags> <exptime> <bytes> [noreply]`
+//! - `cas <key> <flags> <exptime> <bytes> <cas unique> [noreply]`
+//! - `delete <key> [noreply]`
+//! - `touch <key> <exptime> [noreply]`
+//! - `incr <key> <value> [noreply]` and `decr <key> <value> [noreply]`
+//! - `version` and `quit`
+//!
+//! Every write is committed to the local state before it is answered, so a `get` right after it
+//! always sees it. `add`, `replace`, `cas` and `touch` check the current value and write the new
+//! one at once. The state has no way to change the TTL of a value alone, so `touch` writes the
+//! value again with the new expiration time and bumps its timestamp, which is also its CAS unique.
+//!
+//! `append`, `prepend`, `incr` and `decr` change the current value and keep its expiration time,
+//! ignoring the `exptime` of the command. `incr` wraps around at 64 bits and `decr` stops at `0`,
+//! like memcached.
+//!
+//! # Expiration
+//! The `exptime` is mapped to the `ttl` of the value. Like memcached, `0` never expires, up to
+//! 30 days is the number of seconds from now and anything larger is a Unix time in seconds. A
+//! negative or past expiration time removes the value.
+//!
+//! # Values
+//! Values in the state are JSON. A value that is stored by this agent is always kept as a JSON
+//! string, so it is returned exactly as it was stored, even if it looks like JSON. Values that are
+//! set by other agents and are not strings are returned as JSON text. Flags are accepted but not
+//! stored, so values are always returned with flags `0`.
+//!
+//! Only UTF-8 values of up to 1 MiB are accepted, and command lines of up to 64 KiB.
+
+use crate::agent;
+use crate::helpers::text::{from_text, read_block, read_line, to_text};
+use crate::helpers::utils::epoch;
+use crate::state::{self, Condition, StateValue};
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+type Result<T> = std::result::Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// The maximum length of a key, the same as memcached.
+const MAX_KEY_LEN: usize = 250;
+
+/// The maximum size of a value, the same as the default of memcached.
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+
+/// The maximum length of a command line, enough for a `get` of a few hundred keys.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// Expiration times up to 30 days are relative, anything larger is a Unix time.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+/// The Memcached struct.
+///
+/// This struct holds information loaded from the agent configuration.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Memcached {
+    /// Binds and accepts connections on this port.
+    /// Default port: 11211
+    port: u16,
+}
+
+/// Default values for this implementation.
+impl std::default::Default for Memcached {
+    fn default() -> Self {
+        Memcached { port: 11211 }
+    }
+}
+
+/// A storage command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Store {
+    Set,
+    Add,
+    Replace,
+    Append,
+    Prepend,
+    Cas(u64),
+}
+
+impl Store {
+    /// Returns the condition the current value must meet for the value to be stored.
+    fn condition(self) -> Condition {
+        match self {
+            Store::Set => Condition::Always,
+            Store::Add => Condition::Missing,
+            Store::Replace | Store::Append | Store::Prepend => Condition::Exists,
+            Store::Cas(cas) => Condition::Ts(cas),
+        }
+    }
+}
+
+/// Maps an expiration time to a TTL in milliseconds.
+///
+/// Returns `Some(None)` for values that never expire and `None` for values that already expired.
+fn ttl(exptime: i64) -> Option<Option<u64>> {
+    match exptime {
+        0 => Some(None),
+        e if e < 0 => None,
+        e if e <= MAX_RELATIVE_EXPTIME => Some(Some(e as u64 * 1000)),
+        e => match (e as u64).saturating_mul(1000).saturating_sub(epoch()) {
+            0 => None,
+            ttl => Some(Some(ttl)),
+        },
+    }
+}
+
+/// Handles the commands of all connections.
+struct Handler {
+    state: state::SafeState,
+}
+
+impl Handler {
+    /// Returns the values of the keys that exist, from a single snapshot of the state.
+    fn get(&self, keys: &[&str], cas: bool) -> Vec<u8> {
+        let batch = serde_json::to_vec(keys)
+            .map_err(|e| e.into())
+            .and_then(|keys| self.state.get_many(&keys as &dyn StateValue))
+            .ok()
+            .and_then(|batch| batch.as_bytes())
+            .and_then(|batch| serde_json::from_slice::<serde_json::Value>(&batch).ok());
+
+        let batch = match batch {
+            Some(batch) => batch,
+            None => return b"SERVER_ERROR failed to read the values\r\n".to_vec(),
+        };
+
+        let mut out = Vec::new();
+        for key in keys {
+            let found = match batch["found"].get(key) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            let value = to_text(serde_json::to_vec(&found["value"]).unwrap_or_default());
+            match (cas, found["ts"].as_u64()) {
+                (true, Some(ts)) => out.extend_from_slice(format!("VALUE {} 0 {} {}\r\n", key, value.len(), ts).as_bytes()),
+                _ => out.extend_from_slice(format!("VALUE {} 0 {}\r\n", key, value.len()).as_bytes()),
+            }
+            out.extend_from_slice(&value);
+            out.extend_from_slice(b"\r\n");
+        }
+
+        out.extend_from_slice(b"END\r\n");
+        out
+    }
+
+    /// Stores a value and returns the reply.
+    fn store(&self, command: Store, key: &str, value: &str, exptime: i64) -> &'static str {
+        let updated = match command {
+            Store::Append => self.update(key, |current| Ok((format!("{}{}", current, value), ()))),
+            Store::Prepend => self.update(key, |current| Ok((format!("{}{}", value, current), ()))),
+            _ => return self.write(command, key, value, exptime),
+        };
+
+        match updated {
+            Ok(Some(())) => "STORED\r\n",
+            Ok(None) => "NOT_STORED\r\n",
+            Err(reply) => reply,
+        }
+    }
+
+    /// Writes a new value for a `set`, `add`, `replace` or `cas` and returns the reply.
+    fn write(&self, command: Store, key: &str, value: &str, exptime: i64) -> &'static str {
+        let key = key.to_string();
+        let value = from_text(value);
+
+        let stored = match ttl(exptime) {
+            Some(ttl) => self
+                .state
+                .insert_if(&key as &dyn StateValue, &value as &dyn StateValue, ttl, command.condition())
+                .map(|ts| ts.is_some()),
+            None => self.expire(&key, command.condition()),
+        };
+
+        match stored {
+            Ok(true) => "STORED\r\n",
+            Ok(false) => match command {
+                Store::Cas(_) if self.state.get(&key as &dyn StateValue).is_some() => "EXISTS\r\n",
+                Store::Cas(_) => "NOT_FOUND\r\n",
+                _ => "NOT_STORED\r\n",
+            },
+            Err(e) => {
+                warn!("Failed to store {}; {}", key, e);
+                "SERVER_ERROR failed to store the value\r\n"
+            }
+        }
+    }
+
+    /// Handles a value that is stored with an expiration time in the past.
+    ///
+    /// Such a value would expire right away, so the current value is deleted instead if the
+    /// condition holds. Returns whether the condition held.
+    fn expire(&self, key: &String, condition: Condition) -> std::result::Result<bool, Box<dyn StdError>> {
+        match condition {
+            Condition::Missing => Ok(self.state.get(key as &dyn StateValue).is_none()),
+            Condition::Always => self.state.delete_if(key as &dyn StateValue, Condition::Exists).map(|_| true),
+            condition => self.state.delete_if(key as &dyn StateValue, condition).map(|ts| ts.is_some()),
+        }
+    }
+
+    /// Replaces the value of an existing key with the one returned by `update`, keeping its
+    /// expiration time.
+    ///
+    /// The new value is written only if the value did not change since it was read, and the
+    /// update is tried again if it did. Returns `None` if the key does not exist, or the error
+    /// reply if `update` fails or the value cannot be written.
+    fn update<T>(
+        &self,
+        key: &str,
+        update: impl Fn(&str) -> std::result::Result<(String, T), &'static str>,
+    ) -> std::result::Result<Option<T>, &'static str> {
+        let key = key.to_string();
+
+        loop {
+            let current = self.state.get(&key as &dyn StateValue).and_then(|value| {
+                let expires = value
+                    .as_bytes()
+                    .and_then(|value| serde_json::from_slice::<serde_json::Value>(&value).ok())
+                    .and_then(|value| Some(value["ts"].as_u64()? + value["ttl"].as_u64()?));
+                Some((to_text(value.as_raw_bytes()?), value.ts()?, expires))
+            });
+
+            let (value, ts, expires) = match current {
+                Some(current) => current,
+                None => return Ok(None),
+            };
+
+            let (value, result) = update(&String::from_utf8_lossy(&value))?;
+            let value = from_text(&value);
+            let ttl = expires.map(|expires| expires.saturating_sub(epoch()));
+
+            match self.state.insert_if(&key as &dyn StateValue, &value as &dyn StateValue, ttl, Condition::Ts(ts)) {
+                Ok(Some(_)) => return Ok(Some(result)),
+                Ok(None) => continue,
+                Err(e) => {
+                    warn!("Failed to update {}; {}", key, e);
+                    return Err("SERVER_ERROR failed to store the value\r\n");
+                }
+            }
+        }
+    }
+
+    /// Increments or decrements a numeric value and returns the reply.
+    fn incr(&self, key: &str, delta: u64, decr: bool) -> String {
+        let updated = self.update(key, |current| {
+            let current: u64 = current
+                .parse()
+                .map_err(|_| "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n")?;
+            let value = if decr { current.saturating_sub(delta) } else { current.wrapping_add(delta) };
+            Ok((value.to_string(), value))
+        });
+
+        match updated {
+            Ok(Some(value)) => format!("{}\r\n", value),
+            Ok(None) => "NOT_FOUND\r\n".to_string(),
+            Err(reply) => reply.to_string(),
+        }
+    }
+
+    fn delete(&self, key: &str) -> &'static str {
+        let key = key.to_string();
+        match self.state.delete_if(&key as &dyn StateValue, Condition::Exists) {
+            Ok(Some(_)) => "DELETED\r\n",
+            Ok(None) => "NOT_FOUND\r\n",
+            Err(e) => {
+                warn!("Failed to delete {}; {}", key, e);
+                "SERVER_ERROR failed to delete the value\r\n"
+            }
+        }
+    }
+
+    /// Sets a new expiration time for an existing value.
+    ///
+    /// The value is written again with the new TTL and a new timestamp, only if it did not change
+    /// in the meantime.
+    fn touch(&self, key: &str, exptime: i64) -> &'static str {
+        let key = key.to_string();
+        let current = self
+            .state
+            .get(&key as &dyn StateValue)
+            .and_then(|value| Some((value.as_raw_bytes()?, value.ts()?)));
+
+        let (value, ts) = match current {
+            Some(current) => current,
+            None => return "NOT_FOUND\r\n",
+        };
+
+        let touched = match ttl(exptime) {
+            Some(ttl) => self
+                .state
+                .insert_if(&key as &dyn StateValue, &value as &dyn StateValue, ttl, Condition::Ts(ts))
+                .map(|ts| ts.is_some()),
+            None => self.expire(&key, Condition::Ts(ts)),
+        };
+
+        match touched {
+            Ok(true) => "TOUCHED\r\n",
+            Ok(false) => "NOT_FOUND\r\n",
+            Err(e) => {
+                warn!("Failed to touch {}; {}", key, e);
+                "SERVER_ERROR failed to touch the value\r\n"
+            }
+        }
+    }
+}
+
+/// A parsed command line.
+#[derive(Debug, PartialEq)]
+enum Command<'a> {
+    Get { keys: Vec<&'a str>, cas: bool },
+    Store { command: Store, key: &'a str, exptime: i64, bytes: usize, noreply: bool },
+    Delete { key: &'a str, noreply: bool },
+    Touch { key: &'a str, exptime: i64, noreply: bool },
+    Incr { key: &'a str, delta: u64, decr: bool, noreply: bool },
+    Version,
+    Quit,
+}
+
+/// Parses a command line.
+///
+/// Returns the error reply if the command is unknown or malformed.
+fn parse(line: &str) -> std::result::Result<Command<'_>, &'static str> {
+    let mut args: Vec<&str> = line.split_ascii_whitespace().collect();
+    let noreply = args.last() == Some(&"noreply");
+    if noreply {
+        args.pop();
+    }
+
+    if args.iter().skip(1).any(|key| key.len() > MAX_KEY_LEN) {
+        return Err("CLIENT_ERROR bad command line format\r\n");
+    }
+
+    let number = |arg: &str| arg.parse::<i64>().map_err(|_| "CLIENT_ERROR bad command line format\r\n");
+    let size = |arg: &str| arg.parse::<usize>().map_err(|_| "CLIENT_ERROR bad command line format\r\n");
+    let command = match args.as_slice() {
+        ["get", keys @ ..] if !keys.is_empty() => Command::Get { keys: keys.to_vec(), cas: false },
+        ["gets", keys @ ..] if !keys.is_empty() => Command::Get { keys: keys.to_vec(), cas: true },
+        [command @ "set", key, flags, exptime, bytes]
+        | [command @ "add", key, flags, exptime, bytes]
+        | [command @ "replace", key, flags, exptime, bytes]
+        | [command @ "append", key, flags, exptime, bytes]
+        | [command @ "prepend", key, flags, exptime, bytes] => {
+            number(flags)?;
+            let command = match *command {
+                "set" => Store::Set,
+                "add" => Store::Add,
+                "replace" => Store::Replace,
+                "append" => Store::Append,
+                _ => Store::Prepend,
+            };
+            Command::Store { command, key, exptime: number(exptime)?, bytes: size(bytes)?, noreply }
+        }
+        ["cas", key, flags, exptime, bytes, cas] => {
+            number(flags)?;
+            let cas = cas.parse::<u64>().map_err(|_| "CLIENT_ERROR bad command line format\r\n")?;
+            Command::Store { command: Store::Cas(cas), key, exptime: number(exptime)?, bytes: size(bytes)?, noreply }
+        }
+        ["delete", key] | ["delete", key, "0"] => Command::Delete { key, noreply },
+        ["touch", key, exptime] => Command::Touch { key, exptime: number(exptime)?, noreply },
+        [command @ "incr", key, delta] | [command @ "decr", key, delta] => {
+            let delta = delta.parse::<u64>().map_err(|_| "CLIENT_ERROR invalid numeric delta argument\r\n")?;
+            Command::Incr { key, delta, decr: *command == "decr", noreply }
+        }
+        ["version"] => Command::Version,
+        ["quit"] => Command::Quit,
+        _ => return Err("ERROR\r\n"),
+    };
+
+    Ok(command)
+}
+
+/// Reads the data block of a storage command.
+///
+/// Returns the error reply if the data block is too large, malformed or not valid UTF-8. Data
+/// blocks that are too large are skipped without being kept in memory.
+async fn read_data<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    bytes: usize,
+) -> Result<std::result::Result<String, &'static str>> {
+    if bytes > MAX_VALUE_LEN {
+        tokio::io::copy(&mut reader.take(bytes as u64 + 2), &mut tokio::io::sink()).await?;
+        return Ok(Err("SERVER_ERROR object too large for cache\r\n"));
+    }
+
+    let data = match read_block(reader, bytes).await? {
+        Some(data) => data,
+        None => return Ok(Err("CLIENT_ERROR bad data chunk\r\n")),
+    };
+
+    Ok(String::from_utf8(data).map_err(|_| "CLIENT_ERROR values must be valid UTF-8\r\n"))
+}
+
+/// Serves a single connection until it is closed.
+async fn connection(handler: Arc<Handler>, mut stream: TcpStream) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    serve(&handler, reader, &mut writer).await
+}
+
+/// Handles the commands that are read from `reader` until the end of the stream or a `quit`.
+///
+/// Replies are written once all the pipelined commands that were already received are handled.
+async fn serve<R, W>(handler: &Handler, reader: R, writer: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut out = Vec::new();
+
+    loop {
+        let line = match read_line(&mut reader, MAX_LINE_LEN).await? {
+            Some(line) => String::from_utf8_lossy(&line).into_owned(),
+            None => return Ok(()),
+        };
+
+        match parse(&line) {
+            Ok(Command::Get { keys, cas }) => out.extend_from_slice(&handler.get(&keys, cas)),
+            Ok(Command::Store { command, key, exptime, bytes, noreply }) => {
+                let reply = match read_data(&mut reader, bytes).await? {
+                    Ok(value) => handler.store(command, key, &value, exptime),
+                    Err(reply) => reply,
+                };
+                if !noreply {
+                    out.extend_from_slice(reply.as_bytes());
+                }
+            }
+            Ok(Command::Delete { key, noreply }) => {
+                let reply = handler.delete(key);
+                if !noreply {
+                    out.extend_from_slice(reply.as_bytes());
+                }
+            }
+            Ok(Command::Touch { key, exptime, noreply }) => {
+                let reply = handler.touch(key, exptime);
+                if !noreply {
+                    out.extend_from_slice(reply.as_bytes());
+                }
+            }
+            Ok(Command::Incr { key, delta, decr, noreply }) => {
+                let reply = handler.incr(key, delta, decr);
+                if !noreply {
+                    out.extend_from_slice(reply.as_bytes());
+                }
+            }
+            Ok(Command::Version) => out.extend_from_slice(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")).as_bytes()),
+            Ok(Command::Quit) => return Ok(writer.write_all(&out).await?),
+            Err(reply) => out.extend_from_slice(reply.as_bytes()),
+        }
+
+        if reader.buffer().is_empty() {
+            writer.write_all(&out).await?;
+            out.clear();
+        }
+    }
+}
+
+impl Memcached {
+    async fn server(&self, state: state::SafeState) -> Result<()> {
+        let mut listener = TcpListener::bind(("0.0.0.0", self.port)).await?;
+        let handler = Arc::new(Handler { state });
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let handler = handler.clone();
+
+            tokio::spawn(async move {
+                debug!("Accepted memcached connection from {}", addr);
+                if let Err(e) = connection(handler, stream).await {
+                    warn!("Memcached connection from {} failed; {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+#[typetag::serde]
+impl agent::Agent for Memcached {
+    /// Starts the server while passing the current state to be used by the connections.
+    fn start<'a>(&'a self, state: state::SafeState) -> BoxFuture<'a, Result<()>> {
+        self.server(state).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> Handler {
+        Handler { state: Arc::new(state::default::Default::default()) }
+    }
+
+    fn get(handler: &Handler, key: &str) -> Option<String> {
+        let value = handler.state.get(&key.to_string() as &dyn StateValue)?;
+        String::from_utf8(to_text(value.as_raw_bytes()?)).ok()
+    }
+
+    /// Returns the timestamp and the TTL of a value.
+    fn meta(handler: &Handler, key: &str) -> (u64, Option<u64>) {
+        let value = handler.state.get(&key.to_string() as &dyn StateValue).unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&value.as_bytes().unwrap()).unwrap();
+        (value["ts"].as_u64().unwrap(), value["ttl"].as_u64())
+    }
+
+    #[test]
+    fn should_parse_commands() {
+        assert_eq!(parse("get cat dog"), Ok(Command::Get { keys: vec!["cat", "dog"], cas: false }));
+        assert_eq!(parse("gets cat"), Ok(Command::Get { keys: vec!["cat"], cas: true }));
+        assert_eq!(
+            parse("set cat 0 60 8"),
+            Ok(Command::Store { command: Store::Set, key: "cat", exptime: 60, bytes: 8, noreply: false })
+        );
+        assert_eq!(
+            parse("prepend cat 1 -1 3 noreply"),
+            Ok(Command::Store { command: Store::Prepend, key: "cat", exptime: -1, bytes: 3, noreply: true })
+        );
+        assert_eq!(
+            parse("cas cat 0 0 8 42"),
+            Ok(Command::Store { command: Store::Cas(42), key: "cat", exptime: 0, bytes: 8, noreply: false })
+        );
+        assert_eq!(parse("delete cat noreply"), Ok(Command::Delete { key: "cat", noreply: true }));
+        assert_eq!(parse("touch cat 10"), Ok(Command::Touch { key: "cat", exptime: 10, noreply: false }));
+        assert_eq!(parse("incr cat 5"), Ok(Command::Incr { key: "cat", delta: 5, decr: false, noreply: false }));
+        assert_eq!(parse("decr cat 1 noreply"), Ok(Command::Incr { key: "cat", delta: 1, decr: true, noreply: true }));
+        assert_eq!(parse("version"), Ok(Command::Version));
+    }
+
+    #[test]
+    fn should_reject_malformed_commands() {
+        let format = Err("CLIENT_ERROR bad command line format\r\n");
+
+        assert_eq!(parse(""), Err("ERROR\r\n"));
+        assert_eq!(parse("get"), Err("ERROR\r\n"));
+        assert_eq!(parse("fly cat"), Err("ERROR\r\n"));
+        assert_eq!(parse("set cat 0 60"), Err("ERROR\r\n"));
+        assert_eq!(parse("delete cat 10"), Err("ERROR\r\n"));
+        assert_eq!(parse("set cat garfield 60 8"), format);
+        assert_eq!(parse("set cat 0 soon 8"), format);
+        assert_eq!(parse("set cat 0 60 -8"), format);
+        assert_eq!(parse("cas cat 0 0 8 -1"), format);
+        assert_eq!(parse(&format!("get cat {}", "a".repeat(MAX_KEY_LEN + 1))), format);
+        assert_eq!(parse("incr cat -1"), Err("CLIENT_ERROR invalid numeric delta argument\r\n"));
+    }
+
+    #[test]
+    fn should_map_expiration_times_to_ttls() {
+        let now = (epoch() / 1000) as i64;
+
+        assert_eq!(ttl(0), Some(None));
+        assert_eq!(ttl(-1), None);
+        assert_eq!(ttl(60), Some(Some(60000)));
+        assert_eq!(ttl(MAX_RELATIVE_EXPTIME), Some(Some(MAX_RELATIVE_EXPTIME as u64 * 1000)));
+        assert_eq!(ttl(MAX_RELATIVE_EXPTIME + 1), None);
+        assert_eq!(ttl(now - 60), None);
+
+        let absolute = ttl(now + 120).unwrap().unwrap();
+        assert!((119000..=120000).contains(&absolute));
+    }
+
+    #[test]
+    fn should_store_values_only_if_the_condition_holds() {
+        let handler = handler();
+
+        assert_eq!(handler.store(Store::Add, "cat", "garfield", 0), "STORED\r\n");
+        assert_eq!(handler.store(Store::Add, "cat", "tom", 0), "NOT_STORED\r\n");
+        assert_eq!(get(&handler, "cat"), Some("garfield".to_string()));
+
+        assert_eq!(handler.store(Store::Replace, "dog", "odie", 0), "NOT_STORED\r\n");
+        assert_eq!(get(&handler, "dog"), None);
+        assert_eq!(handler.store(Store::Replace, "cat", "tom", 0), "STORED\r\n");
+        assert_eq!(get(&handler, "cat"), Some("tom".to_string()));
+
+        assert_eq!(handler.store(Store::Append, "cat", "!", 0), "STORED\r\n");
+        assert_eq!(handler.store(Store::Prepend, "cat", "1.50 ", 0), "STORED\r\n");
+        assert_eq!(get(&handler, "cat"), Some("1.50 tom!".to_string()));
+        assert_eq!(handler.store(Store::Append, "dog", "!", 0), "NOT_STORED\r\n");
+        assert_eq!(handler.store(Store::Prepend, "dog", "!", 0), "NOT_STORED\r\n");
+
+        let (ts, _) = meta(&handler, "cat");
+        assert_eq!(handler.store(Store::Cas(ts - 1), "cat", "garfield", 0), "EXISTS\r\n");
+        assert_eq!(handler.store(Store::Cas(ts), "cat", "garfield", 0), "STORED\r\n");
+        assert_eq!(handler.store(Store::Cas(ts), "cat", "tom", 0), "EXISTS\r\n");
+        assert_eq!(handler.store(Store::Cas(ts), "dog", "odie", 0), "NOT_FOUND\r\n");
+        assert_eq!(get(&handler, "cat"), Some("garfield".to_string()));
+
+        assert_eq!(handler.store(Store::Set, "cat", "tom", -1), "STORED\r\n");
+        assert_eq!(get(&handler, "cat"), None);
+        assert_eq!(handler.store(Store::Replace, "cat", "tom", -1), "NOT_STORED\r\n");
+    }
+
+    #[test]
+    fn should_keep_the_expiration_time_of_changed_values() {
+        let handler = handler();
+
+        assert_eq!(handler.store(Store::Set, "cat", "garfield", 60), "STORED\r\n");
+        assert_eq!(handler.store(Store::Append, "cat", "!", 0), "STORED\r\n");
+        assert_eq!(handler.incr("cat", 1, false), "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n");
+
+        let (ts, ttl) = meta(&handler, "cat");
+        let expires = ts + ttl.unwrap();
+        assert!(epoch() + 59000 <= expires && expires <= epoch() + 60010);
+    }
+
+    #[test]
+    fn should_touch_values() {
+        let handler = handler();
+        assert_eq!(handler.touch("cat", 60), "NOT_FOUND\r\n");
+
+        handler.store(Store::Set, "cat", "garfield", 0);
+        let (ts, _) = meta(&handler, "cat");
+
+        assert_eq!(handler.touch("cat", 60), "TOUCHED\r\n");
+        let (touched, ttl) = meta(&handler, "cat");
+        assert!(touched > ts);
+        assert_eq!(ttl, Some(60000));
+        assert_eq!(get(&handler, "cat"), Some("garfield".to_string()));
+
+        assert_eq!(handler.touch("cat", 0), "TOUCHED\r\n");
+        assert_eq!(meta(&handler, "cat").1, None);
+
+        assert_eq!(handler.touch("cat", -1), "TOUCHED\r\n");
+        assert_eq!(get(&handler, "cat"), None);
+    }
+
+    #[test]
+    fn should_increment_and_decrement_values() {
+        let handler = handler();
+        assert_eq!(handler.incr("count", 1, false), "NOT_FOUND\r\n");
+
+        handler.store(Store::Set, "count", "10", 0);
+        assert_eq!(handler.incr("count", 5, false), "15\r\n");
+        assert_eq!(handler.incr("count", 20, true), "0\r\n");
+        assert_eq!(handler.incr("count", u64::MAX, false), format!("{}\r\n", u64::MAX));
+        assert_eq!(handler.incr("count", 2, false), "1\r\n");
+        assert_eq!(get(&handler, "count"), Some("1".to_string()));
+
+        handler.store(Store::Set, "count", "-1", 0);
+        assert_eq!(handler.incr("count", 1, true), "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n");
+    }
+
+    #[test]
+    fn should_delete_values() {
+        let handler = handler();
+        handler.store(Store::Set, "cat", "garfield", 0);
+
+        assert_eq!(handler.delete("cat"), "DELETED\r\n");
+        assert_eq!(get(&handler, "cat"), None);
+        assert_eq!(handler.delete("cat"), "NOT_FOUND\r\n");
+    }
+
+    #[tokio::test]
+    async fn should_serve_commands() {
+        let handler = handler();
+        let commands = concat!(
+            "set cat 0 0 4\r\n1.50\r\n",
+            "append cat 0 0 1 noreply\r\n!\r\n",
+            "get cat dog\r\n",
+            "incr cat 1\r\n",
+            "quit\r\n",
+            "get cat\r\n",
+        );
+
+        let mut out = Vec::new();
+        serve(&handler, commands.as_bytes(), &mut out).await.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "STORED\r\n",
+                "VALUE cat 0 5\r\n1.50!\r\nEND\r\n",
+                "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n",
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn should_close_connections_with_lines_that_are_too_long() {
+        let handler = handler();
+
+        let mut line = vec![b'a'; MAX_LINE_LEN + 1];
+        line.extend_from_slice(b"\r\nversion\r\n");
+        assert!(serve(&handler, &line[..], &mut Vec::new()).await.is_err());
+
+        let commands = b"set cat 0 0 3\r\ngarfield\r\nversion\r\n";
+        let mut out = Vec::new();
+        serve(&handler, &commands[..], &mut out).await.unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("CLIENT_ERROR bad data chunk\r\nERROR\r\nVERSION "));
+    }
+}
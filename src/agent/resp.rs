@@ -0,0 +1,749 @@
+//! A Redis (RESP2) implementation of the Agent trait.
+//!
+//! This agent speaks a subset of the Redis protocol so services that already use a Redis client
+//! can read and write the state without any code changes.
+//!
+//! # Configuration
+//!
+//! ```yaml
+//! agent:
+//!   kind: Resp
+//!   port: 6379
+//!   max_bulk_len: 16777216
+//! ```
+//!
+//! # Commands
+//! The following commands are supported. Both RESP arrays and inline commands are accepted.
+//!
+//! - `PING [message]`
+//! - `GET key`
+//! - `SET key value [EX seconds|PX milliseconds] [NX|XX]` - `EX` and `PX` are mapped to the `ttl`
+//!   of the value. With `NX` or `XX`, the value is set only if the key is missing or exists.
+//! - `MGET key [key ...]`
+//! - `DEL key [key ...]`
+//! - `EXISTS key [key ...]`
+//! - `TTL key` and `PTTL key`
+//! - `KEYS pattern`
+//! - `SCAN cursor [MATCH pattern] [COUNT count]`
+//! - `QUIT`
+//!
+//! # Values
+//! Values in the state are JSON, while Redis values are strings. A value that is set with `SET` is
+//! always stored as a JSON string, so `GET` returns exactly the bytes that were set, even if they
+//! look like JSON (`1.50` is not turned into `1.5`). Values that are set by other agents and are
+//! not strings are returned as JSON text.
+//!
+//! `SET` and `DEL` are committed to the local state before they are answered, so a `GET` right
+//! after them always sees the write.
+//!
+//! Keys and values must be valid UTF-8. Inline commands and the headers of RESP arrays are limited
+//! to 64 KiB, and every argument to `max_bulk_len` bytes.
+//!
+//! # Cursors
+//! `SCAN` cursors are numbers that refer to the position of a scan on the agent. Only the most
+//! recent cursors are kept, so a scan that is not continued for a long time may fail with an
+//! invalid cursor error and needs to be restarted from `0`.
+
+use crate::agent;
+use crate::helpers::text::{from_text, read_block, read_line, to_text};
+use crate::helpers::utils::epoch;
+use crate::state::{self, Condition, StateValue};
+use futures::future::{BoxFuture, FutureExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error as StdError;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+type Result<T> = std::result::Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// The maximum number of arguments in a single command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// The maximum length of a line, the same as the maximum length of an inline command in Redis.
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// The maximum number of scan cursors that are kept.
+const MAX_CURSORS: usize = 4096;
+
+/// The number of keys returned by `SCAN` when `COUNT` is not specified.
+const DEFAULT_COUNT: usize = 10;
+
+/// The Resp struct.
+///
+/// This struct holds information loaded from the agent configuration.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct Resp {
+    /// Binds and accepts connections on this port.
+    /// Default port: 6379
+    port: u16,
+
+    /// The maximum length in bytes of a single argument of a command.
+    /// Default value: 16 MiB (16777216 bytes).
+    max_bulk_len: usize,
+}
+
+/// Default values for this implementation.
+impl std::default::Default for Resp {
+    fn default() -> Self {
+        Resp { port: 6379, max_bulk_len: 16 * 1024 * 1024 }
+    }
+}
+
+/// A reply to a command.
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn wrong_args(command: &str) -> Reply {
+        Reply::Error(format!("ERR wrong number of arguments for '{}' command", command))
+    }
+
+    fn syntax_error() -> Reply {
+        Reply::Error("ERR syntax error".to_string())
+    }
+
+    fn not_an_integer() -> Reply {
+        Reply::Error("ERR value is not an integer or out of range".to_string())
+    }
+
+    /// Encodes the reply in RESP2.
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(status) => out.extend_from_slice(format!("+{}\r\n", status).as_bytes()),
+            Reply::Error(e) => out.extend_from_slice(format!("-{}\r\n", e.replace(&['\r', '\n'][..], " ")).as_bytes()),
+            Reply::Integer(i) => out.extend_from_slice(format!(":{}\r\n", i).as_bytes()),
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Bulk(Some(bulk)) => {
+                out.extend_from_slice(format!("${}\r\n", bulk.len()).as_bytes());
+                out.extend_from_slice(bulk);
+                out.extend_from_slice(b"\r\n");
+            }
+            Reply::Array(replies) => {
+                out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+                for reply in replies {
+                    reply.write(out);
+                }
+            }
+        }
+    }
+}
+
+/// The cursors of the scans that are in progress.
+///
+/// Maps the numeric cursors that are returned to the clients to the last key of each page. The
+/// oldest cursors are dropped once there are more than [MAX_CURSORS].
+#[derive(Default)]
+struct Cursors {
+    last: u64,
+    keys: VecDeque<(u64, String)>,
+}
+
+impl Cursors {
+    fn insert(&mut self, key: String) -> u64 {
+        self.last += 1;
+        self.keys.push_back((self.last, key));
+        if self.keys.len() > MAX_CURSORS {
+            self.keys.pop_front();
+        }
+
+        self.last
+    }
+
+    fn get(&self, cursor: u64) -> Option<String> {
+        self.keys.iter().find(|(c, _)| *c == cursor).map(|(_, key)| key.clone())
+    }
+}
+
+/// Handles the commands of all connections.
+struct Handler {
+    state: state::SafeState,
+    cursors: Arc<Mutex<Cursors>>,
+}
+
+impl Handler {
+    /// Handles a single command and returns the reply.
+    fn handle(&self, args: Vec<Vec<u8>>) -> Reply {
+        let mut args = args.into_iter();
+        let command = match args.next() {
+            Some(command) => String::from_utf8_lossy(&command).to_lowercase(),
+            None => return Reply::Error("ERR empty command".to_string()),
+        };
+
+        let args: Vec<String> = match args.map(String::from_utf8).collect() {
+            Ok(args) => args,
+            Err(_) => return Reply::Error("ERR keys and values must be valid UTF-8".to_string()),
+        };
+
+        match (command.as_str(), args.len()) {
+            ("ping", 0) => Reply::Status("PONG"),
+            ("ping", 1) => Reply::Bulk(Some(args[0].clone().into_bytes())),
+            ("get", 1) => self.get(&args[0]),
+            ("set", n) if n >= 2 => self.set(&args),
+            ("mget", n) if n >= 1 => self.mget(args),
+            ("del", n) if n >= 1 => self.del(&args),
+            ("exists", n) if n >= 1 => self.exists(&args),
+            ("ttl", 1) => self.ttl(&args[0], 1000),
+            ("pttl", 1) => self.ttl(&args[0], 1),
+            ("keys", 1) => self.keys(&args[0]),
+            ("scan", n) if n >= 1 => self.scan(&args),
+            ("quit", 0) => Reply::Status("OK"),
+            ("ping", _) | ("get", _) | ("set", _) | ("mget", _) | ("del", _) | ("exists", _) | ("ttl", _)
+            | ("pttl", _) | ("keys", _) | ("scan", _) | ("quit", _) => Reply::wrong_args(&command),
+            _ => Reply::Error(format!("ERR unknown command '{}'", command)),
+        }
+    }
+
+    fn get(&self, key: &str) -> Reply {
+        let value = self
+            .state
+            .get(&key.to_string() as &dyn StateValue)
+            .and_then(|value| value.as_raw_bytes());
+
+        Reply::Bulk(value.map(to_text))
+    }
+
+    /// Sets a value. Replies with nil if the condition of `NX` or `XX` does not hold.
+    fn set(&self, args: &[String]) -> Reply {
+        let mut ttl = None;
+        let mut condition = Condition::Always;
+        let mut options = args[2..].iter();
+        while let Some(option) = options.next() {
+            let unit = match (option.to_lowercase().as_str(), condition) {
+                ("nx", Condition::Always) => {
+                    condition = Condition::Missing;
+                    continue;
+                }
+                ("xx", Condition::Always) => {
+                    condition = Condition::Exists;
+                    continue;
+                }
+                ("ex", _) => 1000,
+                ("px", _) => 1,
+                _ => return Reply::syntax_error(),
+            };
+
+            if ttl.is_some() {
+                return Reply::syntax_error();
+            }
+
+            ttl = match options.next().map(|ttl| ttl.parse::<u64>()) {
+                Some(Ok(ttl)) if ttl > 0 => Some(ttl.saturating_mul(unit)),
+                Some(_) => return Reply::Error("ERR invalid expire time in 'set' command".to_string()),
+                None => return Reply::syntax_error(),
+            };
+        }
+
+        let value = from_text(&args[1]);
+        match self.state.insert_if(&args[0] as &dyn StateValue, &value as &dyn StateValue, ttl, condition) {
+            Ok(Some(_)) => Reply::Status("OK"),
+            Ok(None) => Reply::Bulk(None),
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+
+    fn mget(&self, keys: Vec<String>) -> Reply {
+        let batch = serde_json::to_vec(&keys)
+            .map_err(|e| e.into())
+            .and_then(|keys| self.state.get_many(&keys as &dyn StateValue))
+            .ok()
+            .and_then(|batch| batch.as_bytes())
+            .and_then(|batch| serde_json::from_slice::<serde_json::Value>(&batch).ok());
+
+        let batch = match batch {
+            Some(batch) => batch,
+            None => return Reply::Error("ERR failed to read the values".to_string()),
+        };
+
+        Reply::Array(
+            keys.iter()
+                .map(|key| batch["found"].get(key).and_then(|value| serde_json::to_vec(&value["value"]).ok()))
+                .map(|value| Reply::Bulk(value.map(to_text)))
+                .collect(),
+        )
+    }
+
+    fn del(&self, keys: &[String]) -> Reply {
+        let mut deleted = 0;
+        for key in keys {
+            match self.state.delete_if(key as &dyn StateValue, Condition::Exists) {
+                Ok(Some(_)) => deleted += 1,
+                Ok(None) => {}
+                Err(e) => return Reply::Error(format!("ERR {}", e)),
+            }
+        }
+
+        Reply::Integer(deleted)
+    }
+
+    fn exists(&self, keys: &[String]) -> Reply {
+        Reply::Integer(keys.iter().filter(|key| self.state.get(*key as &dyn StateValue).is_some()).count() as i64)
+    }
+
+    /// Returns the remaining time to live of a key in `unit` milliseconds (rounded), -1 if the key
+    /// has no TTL or -2 if the key does not exist.
+    fn ttl(&self, key: &str, unit: i64) -> Reply {
+        let value = self
+            .state
+            .get(&key.to_string() as &dyn StateValue)
+            .and_then(|value| value.as_bytes())
+            .and_then(|value| serde_json::from_slice::<serde_json::Value>(&value).ok());
+
+        let value = match value {
+            Some(value) => value,
+            None => return Reply::Integer(-2),
+        };
+
+        match (value["ts"].as_u64(), value["ttl"].as_u64()) {
+            (Some(ts), Some(ttl)) => Reply::Integer(((ts + ttl).saturating_sub(epoch()) as i64 + unit / 2) / unit),
+            _ => Reply::Integer(-1),
+        }
+    }
+
+    fn keys(&self, pattern: &str) -> Reply {
+        match self.scan_keys(pattern, None, None) {
+            Ok((keys, _)) => Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key.into_bytes()))).collect()),
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+
+    fn scan(&self, args: &[String]) -> Reply {
+        let cursor = match args[0].parse::<u64>() {
+            Ok(0) => None,
+            Ok(cursor) => match self.cursors.lock().unwrap().get(cursor) {
+                Some(key) => Some(key),
+                None => return Reply::Error("ERR invalid cursor".to_string()),
+            },
+            Err(_) => return Reply::Error("ERR invalid cursor".to_string()),
+        };
+
+        let mut pattern = "*";
+        let mut count = DEFAULT_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match (option.to_lowercase().as_str(), options.next()) {
+                ("match", Some(p)) => pattern = p,
+                ("count", Some(c)) => match c.parse::<usize>() {
+                    Ok(c) if c > 0 => count = c,
+                    Ok(_) => return Reply::syntax_error(),
+                    Err(_) => return Reply::not_an_integer(),
+                },
+                _ => return Reply::syntax_error(),
+            }
+        }
+
+        match self.scan_keys(pattern, Some(count), cursor) {
+            Ok((keys, next)) => {
+                let next = next.map_or(0, |next| self.cursors.lock().unwrap().insert(next));
+                Reply::Array(vec![
+                    Reply::Bulk(Some(next.to_string().into_bytes())),
+                    Reply::Array(keys.into_iter().map(|key| Reply::Bulk(Some(key.into_bytes()))).collect()),
+                ])
+            }
+            Err(e) => Reply::Error(format!("ERR {}", e)),
+        }
+    }
+
+    /// Scans the keys that match a glob-style pattern.
+    ///
+    /// Only the keys that start with the literal prefix of the pattern are scanned. Returns the
+    /// matching keys of the page and the cursor to continue the scan from.
+    fn scan_keys(
+        &self,
+        pattern: &str,
+        limit: Option<usize>,
+        cursor: Option<String>,
+    ) -> std::result::Result<(Vec<String>, Option<String>), Box<dyn StdError>> {
+        let prefix = pattern
+            .find(&['*', '?', '[', '\\'][..])
+            .map_or(pattern, |i| &pattern[..i])
+            .to_string();
+
+        let scan = state::Scan { prefix, limit, cursor, values: false, within: None };
+        let page: serde_json::Value = serde_json::from_slice(&self.state.scan(&scan)?.as_bytes().unwrap_or_default())?;
+
+        let keys = page["keys"]
+            .as_array()
+            .map(|keys| keys.iter().filter_map(|key| key.as_str()))
+            .into_iter()
+            .flatten()
+            .filter(|key| glob(pattern.as_bytes(), str::as_bytes(key)))
+            .map(|key| key.to_string())
+            .collect();
+
+        Ok((keys, page["next"].as_str().map(|next| next.to_string())))
+    }
+}
+
+/// Matches a string against a glob-style pattern the same way Redis does.
+///
+/// Supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape special characters. Every other
+/// token matches exactly one character, so a mismatch only needs to go back to the last `*`. This
+/// keeps the time it takes to match below the length of the pattern times the length of the
+/// string, whatever the pattern.
+fn glob(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+
+    // the position right after the last `*` and the position of the string it matches up to
+    let mut star = None;
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+
+        if let Some(next) = token(pattern, p, string[s]) {
+            p = next;
+            s += 1;
+            continue;
+        }
+
+        match star {
+            Some((after, matched)) => {
+                p = after;
+                s = matched + 1;
+                star = Some((after, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Matches a single character against the token of the pattern at `p`. Returns the position of
+/// the next token if it matches.
+fn token(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if pattern.get(i + 1) == Some(&b'-') && i + 2 < pattern.len() && pattern[i + 2] != b']' {
+                    let (from, to) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+                    matched |= from <= c && c <= to;
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+
+            // an unterminated class matches like Redis, as if it was terminated
+            let next = if i < pattern.len() { i + 1 } else { i };
+            Some(next).filter(|_| matched != negate)
+        }
+        b'\\' if p + 1 < pattern.len() => Some(p + 2).filter(|_| pattern[p + 1] == c),
+        t => Some(p + 1).filter(|_| *t == c),
+    }
+}
+
+/// Parses the length in the header of an array or a bulk string.
+fn length(line: &[u8], max: usize) -> Result<Option<usize>> {
+    let length: i64 = std::str::from_utf8(line)?.parse().map_err(|_| "invalid length")?;
+    match length {
+        l if l < 0 => Ok(None),
+        l if l as usize > max => Err("length is too large".into()),
+        l => Ok(Some(l as usize)),
+    }
+}
+
+/// Reads a single command. Returns `None` at the end of the stream.
+///
+/// Commands are either RESP arrays of bulk strings or inline commands separated by whitespace.
+/// Fails if an argument is longer than `max_bulk_len`.
+async fn read_command<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    max_bulk_len: usize,
+) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader, MAX_LINE_LEN).await? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if line.first() != Some(&b'*') {
+        return Ok(Some(
+            line.split(|c| c.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect(),
+        ));
+    }
+
+    let count = length(&line[1..], MAX_ARGS)?.unwrap_or_default();
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let line = read_line(reader, MAX_LINE_LEN).await?.ok_or("unexpected end of stream")?;
+        if line.first() != Some(&b'$') {
+            return Err(format!("expected '$', got '{}'", String::from_utf8_lossy(&line)).into());
+        }
+
+        let len = length(&line[1..], max_bulk_len)?.ok_or("unexpected null bulk string")?;
+        args.push(read_block(reader, len).await?.ok_or("expected CRLF after a bulk string")?);
+    }
+
+    Ok(Some(args))
+}
+
+/// Serves a single connection until it is closed.
+///
+/// Replies are written once all the pipelined commands that were already received are handled.
+async fn connection(handler: Arc<Handler>, mut stream: TcpStream, max_bulk_len: usize) -> Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut reader = BufReader::new(reader);
+    let mut out = Vec::new();
+
+    loop {
+        let args = match read_command(&mut reader, max_bulk_len).await {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                Reply::Error(format!("ERR Protocol error: {}", e)).write(&mut out);
+                writer.write_all(&out).await?;
+                return Err(e);
+            }
+        };
+
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"quit");
+        handler.handle(args).write(&mut out);
+
+        if quit || reader.buffer().is_empty() {
+            writer.write_all(&out).await?;
+            out.clear();
+        }
+
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+impl Resp {
+    async fn server(&self, state: state::SafeState) -> Result<()> {
+        let mut listener = TcpListener::bind(("0.0.0.0", self.port)).await?;
+        let handler = Arc::new(Handler { state, cursors: Default::default() });
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let handler = handler.clone();
+            let max_bulk_len = self.max_bulk_len;
+
+            tokio::spawn(async move {
+                debug!("Accepted RESP connection from {}", addr);
+                if let Err(e) = connection(handler, stream, max_bulk_len).await {
+                    warn!("RESP connection from {} failed; {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+#[typetag::serde]
+impl agent::Agent for Resp {
+    /// Starts the server while passing the current state to be used by the connections.
+    fn start<'a>(&'a self, state: state::SafeState) -> BoxFuture<'a, Result<()>> {
+        self.server(state).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler() -> Handler {
+        Handler { state: Arc::new(state::default::Default::default()), cursors: Default::default() }
+    }
+
+    fn handle(handler: &Handler, command: &str) -> Reply {
+        handler.handle(command.split_whitespace().map(|arg| arg.as_bytes().to_vec()).collect())
+    }
+
+    fn bulk(value: &str) -> Reply {
+        Reply::Bulk(Some(value.as_bytes().to_vec()))
+    }
+
+    fn integer(reply: Reply) -> i64 {
+        match reply {
+            Reply::Integer(i) => i,
+            reply => panic!("expected an integer, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn should_read_writes_right_away() {
+        let handler = handler();
+
+        assert_eq!(handle(&handler, "SET cat garfield"), Reply::Status("OK"));
+        assert_eq!(handle(&handler, "GET cat"), bulk("garfield"));
+        assert_eq!(handle(&handler, "SET cat 1.50"), Reply::Status("OK"));
+        assert_eq!(handle(&handler, "GET cat"), bulk("1.50"));
+
+        assert_eq!(handle(&handler, "DEL cat dog"), Reply::Integer(1));
+        assert_eq!(handle(&handler, "GET cat"), Reply::Bulk(None));
+        assert_eq!(handle(&handler, "DEL cat"), Reply::Integer(0));
+    }
+
+    #[test]
+    fn should_set_values_only_if_the_condition_holds() {
+        let handler = handler();
+
+        assert_eq!(handle(&handler, "SET cat tom NX"), Reply::Status("OK"));
+        assert_eq!(handle(&handler, "SET cat garfield NX"), Reply::Bulk(None));
+        assert_eq!(handle(&handler, "GET cat"), bulk("tom"));
+
+        assert_eq!(handle(&handler, "SET dog odie XX"), Reply::Bulk(None));
+        assert_eq!(handle(&handler, "GET dog"), Reply::Bulk(None));
+        assert_eq!(handle(&handler, "SET cat garfield xx"), Reply::Status("OK"));
+        assert_eq!(handle(&handler, "GET cat"), bulk("garfield"));
+
+        assert_eq!(handle(&handler, "SET cat tom NX XX"), Reply::syntax_error());
+        assert_eq!(handle(&handler, "SET cat tom NX NX"), Reply::syntax_error());
+        assert_eq!(handle(&handler, "SET cat tom EX 10 PX 10"), Reply::syntax_error());
+        assert_eq!(handle(&handler, "SET cat tom EX"), Reply::syntax_error());
+        assert!(matches!(handle(&handler, "SET cat tom EX 0"), Reply::Error(_)));
+        assert!(matches!(handle(&handler, "SET cat tom PX garfield"), Reply::Error(_)));
+        assert_eq!(handle(&handler, "GET cat"), bulk("garfield"));
+    }
+
+    #[test]
+    fn should_map_expiration_times_to_ttls() {
+        let handler = handler();
+
+        assert_eq!(handle(&handler, "TTL cat"), Reply::Integer(-2));
+        assert_eq!(handle(&handler, "PTTL cat"), Reply::Integer(-2));
+
+        handle(&handler, "SET cat garfield");
+        assert_eq!(handle(&handler, "TTL cat"), Reply::Integer(-1));
+        assert_eq!(handle(&handler, "PTTL cat"), Reply::Integer(-1));
+
+        handle(&handler, "SET cat garfield EX 10 NX");
+        assert_eq!(handle(&handler, "TTL cat"), Reply::Integer(-1));
+
+        handle(&handler, "SET cat garfield EX 10");
+        assert_eq!(handle(&handler, "TTL cat"), Reply::Integer(10));
+        // a write within the same millisecond is given a timestamp right after the current one
+        assert!((9000..=10010).contains(&integer(handle(&handler, "PTTL cat"))));
+
+        handle(&handler, "SET cat garfield px 2000 XX");
+        assert_eq!(handle(&handler, "TTL cat"), Reply::Integer(2));
+        assert!((1000..=2010).contains(&integer(handle(&handler, "PTTL cat"))));
+    }
+
+    #[test]
+    fn should_count_keys() {
+        let handler = handler();
+        handle(&handler, "SET cat garfield");
+        handle(&handler, "SET dog odie");
+
+        assert_eq!(
+            handle(&handler, "MGET cat mouse dog"),
+            Reply::Array(vec![bulk("garfield"), Reply::Bulk(None), bulk("odie")])
+        );
+        assert_eq!(handle(&handler, "EXISTS cat mouse cat"), Reply::Integer(2));
+        assert_eq!(handle(&handler, "DEL cat mouse dog cat"), Reply::Integer(2));
+        assert_eq!(handle(&handler, "EXISTS cat dog"), Reply::Integer(0));
+        assert_eq!(handle(&handler, "MGET"), Reply::wrong_args("mget"));
+    }
+
+    #[test]
+    fn should_scan_keys_with_cursors() {
+        let handler = handler();
+        for key in &["cat/1", "cat/2", "cat/3", "cat/4", "cat/5", "dog/1"] {
+            handle(&handler, &format!("SET {} garfield", key));
+        }
+
+        let page = |cursor: &str, keys: &[&str]| {
+            Reply::Array(vec![bulk(cursor), Reply::Array(keys.iter().map(|key| bulk(key)).collect())])
+        };
+
+        assert_eq!(handle(&handler, "SCAN 0 MATCH cat/* COUNT 2"), page("1", &["cat/1", "cat/2"]));
+        assert_eq!(handle(&handler, "SCAN 1 MATCH cat/* COUNT 2"), page("2", &["cat/3", "cat/4"]));
+        assert_eq!(handle(&handler, "SCAN 2 MATCH cat/* COUNT 2"), page("0", &["cat/5"]));
+
+        // a cursor can be continued more than once
+        assert_eq!(handle(&handler, "SCAN 1 MATCH cat/* COUNT 2"), page("3", &["cat/3", "cat/4"]));
+        assert_eq!(handle(&handler, "SCAN 0 MATCH *1"), page("0", &["cat/1", "dog/1"]));
+
+        assert_eq!(handle(&handler, "SCAN 42"), Reply::Error("ERR invalid cursor".to_string()));
+        assert_eq!(handle(&handler, "SCAN cat"), Reply::Error("ERR invalid cursor".to_string()));
+        assert_eq!(handle(&handler, "SCAN 0 COUNT 0"), Reply::syntax_error());
+        assert_eq!(handle(&handler, "SCAN 0 COUNT cat"), Reply::not_an_integer());
+        assert_eq!(handle(&handler, "SCAN 0 MATCH"), Reply::syntax_error());
+
+        assert_eq!(handle(&handler, "KEYS c?t/[1-2]"), Reply::Array(vec![bulk("cat/1"), bulk("cat/2")]));
+    }
+
+    #[test]
+    fn should_match_glob_patterns() {
+        assert!(glob(b"cat*", b"cats"));
+        assert!(glob(b"*", b""));
+        assert!(glob(b"c*t*", b"cat"));
+        assert!(glob(b"c?t", b"cat"));
+        assert!(glob(b"c[ao]t", b"cot"));
+        assert!(glob(b"c[^o]t", b"cat"));
+        assert!(glob(b"c[a-c]t", b"cbt"));
+        assert!(glob(b"c[ao", b"ca"));
+        assert!(glob(b"c\\*t", b"c*t"));
+        assert!(glob(b"*a*b", b"aaxab"));
+        assert!(!glob(b"c\\*t", b"cat"));
+        assert!(!glob(b"c[^a]t", b"cat"));
+        assert!(!glob(b"cat", b"cats"));
+        assert!(!glob(b"cat?", b"cat"));
+        assert!(!glob(b"*a*b", b"aaxa"));
+    }
+
+    #[test]
+    fn should_match_pathological_patterns_quickly() {
+        let key = vec![b'a'; 10000];
+        assert!(!glob(b"*a*a*a*a*a*a*a*a*a*a*a*a*b", &key));
+        assert!(glob(b"*a*a*a*a*a*a*a*a*a*a*a*a*", &key));
+    }
+
+    #[tokio::test]
+    async fn should_read_commands() {
+        let mut reader = BufReader::new(&b"*2\r\n$3\r\nGET\r\n$3\r\ncat\r\nPING  hello\r\n"[..]);
+        assert_eq!(read_command(&mut reader, 16).await.unwrap(), Some(vec![b"GET".to_vec(), b"cat".to_vec()]));
+        assert_eq!(read_command(&mut reader, 16).await.unwrap(), Some(vec![b"PING".to_vec(), b"hello".to_vec()]));
+        assert_eq!(read_command(&mut reader, 16).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn should_bound_the_length_of_lines_and_arguments() {
+        let mut reader = BufReader::new(&b"*1\r\n$17\r\ngarfield-the-cat!\r\n"[..]);
+        assert!(read_command(&mut reader, 16).await.is_err());
+
+        let mut reader = BufReader::new(&b"*1\r\n$16\r\ngarfield"[..]);
+        assert!(read_command(&mut reader, 16).await.is_err());
+
+        let line = vec![b'a'; MAX_LINE_LEN + 1];
+        let mut reader = BufReader::new(&line[..]);
+        assert!(read_command(&mut reader, 16).await.is_err());
+
+        let line = vec![b'a'; MAX_LINE_LEN];
+        let mut reader = BufReader::new(&line[..]);
+        assert_eq!(read_command(&mut reader, 16).await.unwrap(), Some(vec![line.clone()]));
+    }
+}
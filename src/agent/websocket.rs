@@ -0,0 +1,365 @@
+//! A WebSocket implementation of the Agent trait.
+//!
+//! This agent allows an app to open a single connection and multiplex commands over it. Apps
+//! that keep many keys hot can subscribe to the changes of the state and have them pushed instead
+//! of polling the agent.
+//!
+//! Messages are JSON text frames. The values are passed to and from the state as JSON, so this
+//! agent expects a state that serializes its values to JSON, like the [Default] state.
+//!
+//! # Configuration
+//!
+//! ```yaml
+//! agent:
+//!   kind: WebSocket
+//!   port: 3097
+//! ```
+//!
+//! # Commands
+//! Every command is a JSON object with an `op` field. An optional `id` can be specified and is
+//! returned as-is in the reply so the app can match replies to commands.
+//!
+//! ## get
+//! ```json
+//! {"id": 1, "op": "get", "key": "cat"}
+//!
+//! {"id": 1, "ok": true, "value": {"ts": 1601241450390, "ttl": null, "value": "garfield"}}
+//! ```
+//!
+//! ## set
+//! The `value` is passed to the state as-is, the same as the body of a `PUT` request to the
+//! [default agent].
+//! ```json
+//! {"id": 2, "op": "set", "value": {"cat": {"value": "garfield", "ttl": 60000}}}
+//!
+//! {"id": 2, "ok": true}
+//! ```
+//!
+//! ## delete
+//! ```json
+//! {"id": 3, "op": "delete", "key": "cat"}
+//!
+//! {"id": 3, "ok": true}
+//! ```
+//!
+//! ## subscribe and unsubscribe
+//! Subscribes to (or unsubscribes from) the changes of all keys that start with `prefix`. An
+//! empty or missing prefix matches all keys.
+//! ```json
+//! {"id": 4, "op": "subscribe", "prefix": "cat"}
+//!
+//! {"id": 4, "ok": true}
+//! ```
+//!
+//! Changes are then pushed as events. `value` is `null` for deletes and expirations:
+//! ```json
+//! {"event": "set", "key": "cat", "ts": 1601241450390, "value": {"ts": 1601241450390, "ttl": null, "value": "garfield"}}
+//! ```
+//!
+//! If the connection falls too far behind the changes, some changes are dropped and a `lagged`
+//! event is pushed with the number of changes that were missed.
+//!
+//! ## Errors
+//! A command that fails is replied with an error:
+//! ```json
+//! {"id": 1, "ok": false, "error": "not found"}
+//! ```
+//!
+//! [Default]: crate::state::default
+//! [default agent]: crate::agent::default
+
+use crate::agent;
+use crate::state::{self, Change, ChangeKind, StateValue};
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::error::Error as StdError;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, RecvError};
+use tokio_tungstenite::tungstenite::Message;
+
+type Result<T> = std::result::Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// The WebSocket struct.
+///
+/// This struct holds information loaded from the agent configuration.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct WebSocket {
+    /// Binds and accepts connections on this port.
+    /// Default port: 3097
+    port: u16,
+}
+
+/// Default values for this implementation.
+impl std::default::Default for WebSocket {
+    fn default() -> Self {
+        WebSocket { port: 3097 }
+    }
+}
+
+/// A command sent by the app.
+#[derive(Deserialize, Debug)]
+struct Command {
+    /// An optional id that is returned in the reply.
+    id: Option<serde_json::Value>,
+
+    #[serde(flatten)]
+    op: Op,
+}
+
+/// The operation of a command.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Op {
+    Get { key: String },
+    Set { value: serde_json::Value },
+    Delete { key: String },
+    Subscribe {
+        #[serde(default)]
+        prefix: String,
+    },
+    Unsubscribe {
+        #[serde(default)]
+        prefix: String,
+    },
+}
+
+/// A single WebSocket connection.
+///
+/// Holds the prefixes the app subscribed to and the receiver of the changes of the state, which is
+/// only held while there are subscriptions.
+struct Session {
+    state: state::SafeState,
+    prefixes: Vec<String>,
+    changes: Option<broadcast::Receiver<Change>>,
+}
+
+impl Session {
+    /// Handles a command and returns the reply.
+    fn handle(&mut self, text: &str) -> String {
+        let command: Command = match serde_json::from_str(text) {
+            Ok(command) => command,
+            Err(e) => return json!({"ok": false, "error": e.to_string()}).to_string(),
+        };
+
+        let result = match command.op {
+            Op::Get { key } => self.get(key),
+            Op::Set { value } => self.set(value),
+            Op::Delete { key } => self.state.delete(&key as &dyn StateValue).map(|_| None).map_err(|e| e.to_string()),
+            Op::Subscribe { prefix } => self.subscribe(prefix),
+            Op::Unsubscribe { prefix } => self.unsubscribe(prefix),
+        };
+
+        match result {
+            Ok(Some(value)) => json!({"id": command.id, "ok": true, "value": value}),
+            Ok(None) => json!({"id": command.id, "ok": true}),
+            Err(e) => json!({"id": command.id, "ok": false, "error": e}),
+        }
+        .to_string()
+    }
+
+    fn get(&self, key: String) -> std::result::Result<Option<serde_json::Value>, String> {
+        self.state
+            .get(&key as &dyn StateValue)
+            .and_then(|value| value.as_bytes())
+            .and_then(|value| serde_json::from_slice(&value).ok())
+            .map(Some)
+            .ok_or_else(|| "not found".to_string())
+    }
+
+    fn set(&self, value: serde_json::Value) -> std::result::Result<Option<serde_json::Value>, String> {
+        let value = serde_json::to_vec(&value).map_err(|e| e.to_string())?;
+        self.state
+            .set(&value as &dyn StateValue)
+            .map(|_| None)
+            .map_err(|e| e.to_string())
+    }
+
+    fn subscribe(&mut self, prefix: String) -> std::result::Result<Option<serde_json::Value>, String> {
+        if self.changes.is_none() {
+            self.changes = Some(
+                self.state
+                    .subscribe()
+                    .ok_or_else(|| "the state does not support change notification".to_string())?,
+            );
+        }
+
+        if !self.prefixes.contains(&prefix) {
+            self.prefixes.push(prefix);
+        }
+
+        Ok(None)
+    }
+
+    fn unsubscribe(&mut self, prefix: String) -> std::result::Result<Option<serde_json::Value>, String> {
+        self.prefixes.retain(|p| *p != prefix);
+        if self.prefixes.is_empty() {
+            self.changes = None;
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the event to push for a change, if the app subscribed to its key.
+    fn event(&self, change: Change) -> Option<String> {
+        if !self.prefixes.iter().any(|prefix| change.key.starts_with(prefix)) {
+            return None;
+        }
+
+        let value: serde_json::Value = change
+            .value
+            .and_then(|value| value.as_bytes())
+            .and_then(|value| serde_json::from_slice(&value).ok())
+            .unwrap_or_default();
+
+        let event = match change.kind {
+            ChangeKind::Set => "set",
+            ChangeKind::Delete => "delete",
+            ChangeKind::Expire => "expire",
+        };
+
+        Some(json!({"event": event, "key": change.key, "ts": change.ts, "value": value}).to_string())
+    }
+}
+
+/// Receives the next change or waits forever if there are no subscriptions.
+async fn recv(changes: &mut Option<broadcast::Receiver<Change>>) -> std::result::Result<Change, RecvError> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => future::pending().await,
+    }
+}
+
+/// Serves a single connection until it is closed.
+///
+/// Commands are handled in the order they are received and changes are pushed in between.
+async fn connection(state: state::SafeState, stream: TcpStream) -> Result<()> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    let mut session = Session { state, prefixes: Vec::new(), changes: None };
+
+    loop {
+        let outgoing = tokio::select! {
+            message = ws.next() => match message {
+                Some(Ok(Message::Text(text))) => Some(session.handle(&text)),
+                Some(Ok(Message::Close(_))) | None => return Ok(()),
+                Some(Ok(_)) => None,
+                Some(Err(e)) => return Err(e.into()),
+            },
+            change = recv(&mut session.changes) => match change {
+                Ok(change) => session.event(change),
+                Err(RecvError::Lagged(missed)) => Some(json!({"event": "lagged", "missed": missed}).to_string()),
+                Err(RecvError::Closed) => {
+                    session.changes = None;
+                    None
+                }
+            },
+        };
+
+        if let Some(outgoing) = outgoing {
+            ws.send(Message::Text(outgoing)).await?;
+        }
+    }
+}
+
+impl WebSocket {
+    async fn server(&self, state: state::SafeState) -> Result<()> {
+        let mut listener = TcpListener::bind(("0.0.0.0", self.port)).await?;
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let state = state.clone();
+
+            tokio::spawn(async move {
+                debug!("Accepted WebSocket connection from {}", addr);
+                if let Err(e) = connection(state, stream).await {
+                    warn!("WebSocket connection from {} failed; {}", addr, e);
+                }
+            });
+        }
+    }
+}
+
+#[typetag::serde]
+impl agent::Agent for WebSocket {
+    /// Starts the server while passing the current state to be used by the connections.
+    fn start<'a>(&'a self, state: state::SafeState) -> BoxFuture<'a, Result<()>> {
+        self.server(state).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn session() -> Session {
+        let state: state::SafeState = Arc::new(state::default::Default::default());
+        Session { state, prefixes: Vec::new(), changes: None }
+    }
+
+    fn change(kind: ChangeKind, key: &str, value: Option<&str>) -> Change {
+        Change {
+            id: 1,
+            kind,
+            key: key.to_string(),
+            ts: 42,
+            value: value.map(|value| Arc::new(value.to_string()) as Arc<dyn StateValue>),
+        }
+    }
+
+    fn reply(session: &mut Session, command: &str) -> serde_json::Value {
+        serde_json::from_str(&session.handle(command)).unwrap()
+    }
+
+    #[test]
+    fn should_subscribe_and_unsubscribe() {
+        let mut session = session();
+
+        let reply = reply(&mut session, r#"{"id": 4, "op": "subscribe", "prefix": "cat"}"#);
+        assert_eq!(reply, json!({"id": 4, "ok": true}));
+        assert!(session.changes.is_some());
+
+        session.handle(r#"{"op": "subscribe", "prefix": "cat"}"#);
+        session.handle(r#"{"op": "subscribe", "prefix": "dog"}"#);
+        assert_eq!(session.prefixes, vec!["cat", "dog"]);
+
+        session.handle(r#"{"op": "unsubscribe", "prefix": "cat"}"#);
+        assert_eq!(session.prefixes, vec!["dog"]);
+        assert!(session.changes.is_some());
+
+        session.handle(r#"{"op": "unsubscribe", "prefix": "dog"}"#);
+        assert!(session.prefixes.is_empty());
+        assert!(session.changes.is_none());
+    }
+
+    #[test]
+    fn should_encode_events_of_subscribed_keys() {
+        let mut session = session();
+        assert_eq!(session.event(change(ChangeKind::Set, "cat", Some("{}"))), None);
+
+        session.handle(r#"{"op": "subscribe", "prefix": "cat"}"#);
+        assert_eq!(session.event(change(ChangeKind::Set, "dog", Some("{}"))), None);
+
+        let value = r#"{"ts":42,"ttl":null,"value":"garfield"}"#;
+        let event: serde_json::Value = serde_json::from_str(&session.event(change(ChangeKind::Set, "cat", Some(value))).unwrap()).unwrap();
+        assert_eq!(event, json!({"event": "set", "key": "cat", "ts": 42, "value": {"ts": 42, "ttl": null, "value": "garfield"}}));
+
+        let event: serde_json::Value = serde_json::from_str(&session.event(change(ChangeKind::Expire, "cat/tom", None)).unwrap()).unwrap();
+        assert_eq!(event, json!({"event": "expire", "key": "cat/tom", "ts": 42, "value": null}));
+    }
+
+    #[test]
+    fn should_reply_with_errors() {
+        let mut session = session();
+
+        let reply1 = reply(&mut session, r#"{"id": 1, "op": "get", "key": "cat"}"#);
+        assert_eq!(reply1, json!({"id": 1, "ok": false, "error": "not found"}));
+
+        let reply2 = reply(&mut session, r#"{"id": 2, "op": "fly"}"#);
+        assert_eq!(reply2["ok"], false);
+        assert!(reply2["error"].is_string());
+    }
+}
@@ -23,6 +23,7 @@
 //!     kind: Default
 //!     ttl: null
 //!     purge_interval: 10000
+//!     tombstone_ttl: 3600000
 //!     data_seeder:
 //!       kind: File
 //!       filename: data.json
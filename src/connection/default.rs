@@ -23,6 +23,10 @@
 //! ## Pushing
 //! Only the changes from the last publish time will be pushed to other peers.
 //!
+//! Deletes are part of those changes. The [Default](crate::state::default) state keeps a
+//! tombstone for every deleted key, so a delete is pushed and pulled like any other value and
+//! overrides older values on every peer it reaches.
+//!
 //! ## Pulling
 //! When pulling, the connection layer specifies its own state version and if it matches the one 
 //! that the other peer has then nothing will be exchanged. If the versions do not match then 
@@ -31,28 +35,50 @@
 //! The connection layer does not assume anything about the content of the data being exchanged.
 //! The data will be passed as-in to other peers.
 //!
+//! ## Wire format
+//! The state is exchanged as JSON by default. Set `format` to `msgpack` to exchange it as
+//! [MessagePack] instead, which is smaller and faster to parse for large states.
+//!
+//! The format is negotiated with HTTP headers, so peers with different formats can work together,
+//! for example during a rolling upgrade. A pulling peer asks for its format with the `Accept`
+//! header and reads the response by its `Content-Type`. A pushing peer sends MessagePack only to
+//! the peers that answered its last pull with MessagePack, and JSON to any other peer, since peers
+//! that do not read MessagePack (like peers that were not upgraded yet) may not reject it. A peer
+//! is sent JSON until it is pulled from for the first time.
+//!
+//! ```yaml
+//! connection:
+//!   kind: Default
+//!   format: msgpack
+//! ```
+//!
 //! The interval in which the data will be exchanged is set in the `push_interval` and `pull_interval` configuration flags.
 //!
 //! See more about the default implementation and the different options it provides in the [struct documentation].
 //!
 //! [peer provider]: connection::peer_provider
+//! [MessagePack]: https://msgpack.org
 //! [struct documentation]: struct@Default
 
 use crate::connection;
 use crate::connection::peer_provider;
+use crate::helpers::http::format;
 use crate::helpers::http::responses::Responses;
 use crate::helpers::middlewares::json::wrap_json_response;
 use crate::helpers::utils::Sample;
-use crate::state;
+use crate::state::{self, Format};
 use futures::future::{self, BoxFuture, FutureExt, TryFutureExt};
 use futures::{stream, StreamExt};
+use hyper::header::{ACCEPT, CONTENT_TYPE};
 use hyper::{
-    http::Method, service::make_service_fn, service::service_fn, Body, Request, Response, Server,
+    http::Method, StatusCode, service::make_service_fn, service::service_fn, Body, Request, Response, Server,
 };
 use log::{debug, warn};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio;
 use tokio::time;
@@ -95,6 +121,10 @@ pub struct Default {
     /// Default value: 1000ms. 
     timeout: u64,
 
+    /// The format the state is exchanged in with other peers, `json` or `msgpack`.
+    /// Default value: json.
+    format: Format,
+
     /// The peer provider to use.
     ///
     /// The connection layer will reach out to the peer provider to get the full list of available
@@ -102,6 +132,10 @@ pub struct Default {
     ///
     /// Default value: k8s.
     pub peer_provider: Box<dyn peer_provider::PeerProvider>,
+
+    /// The addresses of the peers that answered the last pull with MessagePack.
+    #[serde(skip)]
+    msgpack_peers: Arc<Mutex<HashSet<String>>>,
 }
 
 impl std::default::Default for Default {
@@ -113,37 +147,49 @@ impl std::default::Default for Default {
             pull_interval: 60000,
             r0: 3,
             timeout: 1000,
+            format: Format::Json,
             peer_provider: Box::new(peer_provider::k8s::K8s::default()),
+            msgpack_peers: std::default::Default::default(),
         }
     }
 }
 
-/// Returns an HTTP response with the full state as JSON.
+/// Returns an HTTP response with the full state.
 ///
 /// GET /
+///
+/// The state is returned as JSON, or as MessagePack if the `Accept` header asks for it.
 fn get_handler(state: state::SafeState, req: &Request<Body>) -> Response<Body> {
     let versions_match = req.uri().path().split('/').last().and_then(|version| {
         (version.is_empty() || version != state.version()).into()
     }).unwrap();
 
     if versions_match {
-        Responses::ok(state.get_root().unwrap_or("".into()).into())
+        let format = format::accept(req);
+        match state.get_root() {
+            Some(root) => format::encoded(&*root, format),
+            None => Responses::ok("".into()),
+        }
     } else {
         Responses::no_content()
     }
 }
 
-/// Accepts a JSON body that represents a state. Merges it with its own state.
+/// Accepts a body that represents a state. Merges it with its own state.
 ///
 /// PUT /
+///
+/// The body is JSON, or MessagePack if sent with `Content-Type: application/msgpack`.
 fn set_handler<'a>(
     state: state::SafeState,
     req: Request<Body>,
 ) -> impl FutureExt<Output = Result<Response<Body>>> + 'a {
+    let format = format::content_type(&req);
+
     hyper::body::to_bytes(req.into_body())
         .and_then(move |body| async move {
             let body = &body as &dyn state::StateValue;
-            let result = state.set(body);
+            let result = state.set_encoded(body, format);
 
             Ok(match result {
                 Ok(_) => Responses::no_content(),
@@ -187,6 +233,9 @@ impl Default {
     /// If the current version matches the one of the peer's version then the peer will respond 
     /// with 204 (no content). The full state will be returned by the peer if the versions do not
     /// match.
+    ///
+    /// The state is requested in the configured format and read in the format the peer responded
+    /// with. The format of the response tells whether the peer can be pushed MessagePack.
     async fn receiver(&self, state: state::SafeState) -> Result<()> {
         loop {
             // sample r0 peers
@@ -195,29 +244,46 @@ impl Default {
           
             let res = stream::iter(peers)
                 .map(|peer| {
-                    let url = format!("http://{}:{}/{}", peer.ip(), peer.port().unwrap_or(self.target_port.unwrap_or(self.port)), state.version());
+                    let addr = format!("{}:{}", peer.ip(), peer.port().unwrap_or(self.target_port.unwrap_or(self.port)));
+                    let url = format!("http://{}/{}", addr, state.version());
                     let timeout = self.timeout;
+                    let accept = format::mime(self.format);
+                    let msgpack_peers = self.msgpack_peers.clone();
 
                     tokio::spawn(async move {
                         let client = Client::builder()
                             .connect_timeout(Duration::from_millis(timeout))
                             .build().unwrap();
 
-                        let result = client
+                        let response = client
                             .get(&url.to_string())
+                            .header(ACCEPT, accept)
                             .send()
-                            .await?
-                            .bytes()
-                            .await;
-
-                        result
+                            .await?;
+
+                        // a response without the state (the versions match) does not tell the format
+                        let format = format::from_header(response.headers().get(CONTENT_TYPE));
+                        if response.status() == StatusCode::OK {
+                            let mut msgpack_peers = msgpack_peers.lock().unwrap();
+                            match format {
+                                Format::MessagePack => msgpack_peers.insert(addr),
+                                Format::Json => msgpack_peers.remove(&addr),
+                            };
+                        }
+
+                        response.bytes().await.map(|body| (body, format))
                     })
                 })
                 .buffer_unordered(4);
 
             res.collect::<Vec<_>>().await.iter().for_each(|result| {
-                if let Ok(Ok(result)) = result {
-                    if let Err(e) = state.set(result as &dyn state::StateValue) {
+                if let Ok(Ok((result, format))) = result {
+                    // an empty response means the versions match
+                    if result.is_empty() {
+                        return;
+                    }
+
+                    if let Err(e) = state.set_encoded(result as &dyn state::StateValue, *format) {
                         warn!("Failed to set peer response to state; {}", e);
                     }
                 }
@@ -235,6 +301,9 @@ impl Default {
     /// will randomly pick `r0` peers to exchange the state with.
     ///
     /// It connects to other peers in parallel and publishes the changes since last publish.
+    ///
+    /// The changes are sent in the configured format to the peers that are known to read it, and
+    /// as JSON to any other peer. A peer that rejects them is sent the changes again as JSON.
     async fn publisher(&self, state: state::SafeState) -> Result<()> {
         let mut last_published: Vec<u8> = Vec::<u8>::default();
         let mut last_published_version = String::default();
@@ -247,6 +316,7 @@ impl Default {
 
             let last = last_published.clone();
             let state_clone = state.clone();
+            let format = self.format;
             let res = tokio::task::spawn_blocking(move || {
                 // get the recent state
                 let root = state_clone.get_root().unwrap_or("".into()).as_bytes().unwrap();
@@ -255,11 +325,14 @@ impl Default {
                     return None;
                 }
 
-                let state_to_publish = state_clone.diff(&last).and_then(|diff| {
-                    Ok(diff.as_bytes().unwrap())
-                }).or::<Vec<u8>>(Ok(root.clone())).unwrap();
+                let diff = state_clone.diff(&last).ok();
+                let state_to_publish = diff.as_ref().and_then(|diff| diff.as_bytes()).unwrap_or_else(|| root.clone());
+                let encoded = match format {
+                    Format::Json => None,
+                    format => diff.and_then(|diff| diff.encode(format)).or_else(|| format.encode(&state_to_publish)),
+                };
 
-                Some((state_to_publish, root))
+                Some((state_to_publish, encoded, root))
             }).await?;
 
             if res.is_none() {
@@ -267,7 +340,7 @@ impl Default {
                 continue;
             }
 
-            let (state_to_publish, last) = res.unwrap();
+            let (state_to_publish, encoded, last) = res.unwrap();
             last_published = last;
             last_published_version = state.version();
 
@@ -279,7 +352,9 @@ impl Default {
             let res = stream::iter(peers)
                 .map(|peer| {
                     let state_to_publish = state_to_publish.clone();
-                    let url = format!("http://{}:{}/", peer.ip(), peer.port().unwrap_or(self.target_port.unwrap_or(self.port)));
+                    let addr = format!("{}:{}", peer.ip(), peer.port().unwrap_or(self.target_port.unwrap_or(self.port)));
+                    let encoded = encoded.clone().filter(|_| self.msgpack_peers.lock().unwrap().contains(&addr));
+                    let url = format!("http://{}/", addr);
                     let timeout = self.timeout;
 
                     tokio::spawn(async move {
@@ -288,8 +363,24 @@ impl Default {
                             .build().unwrap();
 
                         debug!("Publishing state to {}", url);
+                        if let Some(encoded) = encoded {
+                            let response = client
+                                .put(&url.to_string())
+                                .header(CONTENT_TYPE, format::mime(format))
+                                .body(encoded)
+                                .send()
+                                .await?;
+
+                            if response.status() != StatusCode::UNPROCESSABLE_ENTITY {
+                                return response.bytes().await;
+                            }
+
+                            debug!("{} rejected the state as {}, publishing it as JSON", url, format::mime(format));
+                        }
+
                         let result = client
                             .put(&url.to_string())
+                            .header(CONTENT_TYPE, format::JSON)
                             .body(state_to_publish)
                             .send()
                             .await?
@@ -2,4 +2,5 @@
 
 pub mod http;
 pub mod middlewares;
+pub mod text;
 pub mod utils;
@@ -1,3 +1,6 @@
 //! A collection of HTTP helper functions.
 //!
+pub mod format;
+pub mod path;
+pub mod query;
 pub mod responses;
@@ -0,0 +1,77 @@
+//! Helpers for negotiating the format of request and response bodies.
+
+use crate::helpers::http::responses::Responses;
+use crate::state::{Format, StateValue};
+use http::{Request, Response};
+use hyper::header::{HeaderValue, ACCEPT, CONTENT_TYPE};
+use hyper::Body;
+
+/// The content type of JSON bodies.
+pub const JSON: &str = "application/json";
+
+/// The content type of MessagePack bodies.
+pub const MSGPACK: &str = "application/msgpack";
+
+/// Returns the format of a body by its content type header.
+///
+/// `application/msgpack` and `application/x-msgpack` are MessagePack. Anything else is treated as
+/// JSON, since JSON is often sent with other content types (`curl -d` sends a form content type).
+pub fn from_header(header: Option<&HeaderValue>) -> Format {
+    let msgpack = header
+        .and_then(|header| header.to_str().ok())
+        .map(|header| header.split(',').any(is_msgpack))
+        .unwrap_or(false);
+
+    if msgpack {
+        Format::MessagePack
+    } else {
+        Format::Json
+    }
+}
+
+/// Returns the format of the request body by its `Content-Type` header.
+pub fn content_type<B>(req: &Request<B>) -> Format {
+    from_header(req.headers().get(CONTENT_TYPE))
+}
+
+/// Returns the format of the response body by the `Accept` header of the request.
+///
+/// MessagePack is returned only if the client explicitly accepts it. Otherwise JSON is returned.
+pub fn accept<B>(req: &Request<B>) -> Format {
+    from_header(req.headers().get(ACCEPT))
+}
+
+/// Returns the content type of the specified format.
+pub fn mime(format: Format) -> &'static str {
+    match format {
+        Format::Json => JSON,
+        Format::MessagePack => MSGPACK,
+    }
+}
+
+/// Returns 200 (ok) with a body in the specified format.
+///
+/// The content type is set for MessagePack bodies only, so JSON responses are left as they were.
+pub fn ok(body: Vec<u8>, format: Format) -> Response<Body> {
+    let mut response = Responses::ok(body.into());
+    if format != Format::Json {
+        response.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(mime(format)));
+    }
+
+    response
+}
+
+/// Returns 200 (ok) with the value encoded in the specified format.
+///
+/// Returns 500 (internal error) if the value failed to encode.
+pub fn encoded(value: &dyn StateValue, format: Format) -> Response<Body> {
+    match value.encode(format) {
+        Some(body) => ok(body, format),
+        None => Responses::internal_error(Some("failed to encode the value".into())),
+    }
+}
+
+fn is_msgpack(media_type: &str) -> bool {
+    let media_type = media_type.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case(MSGPACK) || media_type.eq_ignore_ascii_case("application/x-msgpack")
+}
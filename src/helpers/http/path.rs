@@ -0,0 +1,70 @@
+//! Helpers for reading the path of a request.
+
+use http::Request;
+use percent_encoding::percent_decode_str;
+
+/// Returns the key specified in the path of the request.
+///
+/// The key is the whole path after the leading slash, URL-decoded. This allows keys to hold
+/// slashes (`/tenant/entity/id`) and any other character when percent-encoded
+/// (`/cat%20food`).
+///
+/// Returns `None` if the decoded key is not a valid UTF-8 string.
+pub fn key<B>(req: &Request<B>) -> Option<String> {
+    let path = req.uri().path();
+    let path = path.strip_prefix('/').unwrap_or(path);
+
+    percent_decode_str(path)
+        .decode_utf8()
+        .ok()
+        .map(|key| key.into_owned())
+}
+
+/// Returns the hierarchical prefix specified in the path of the request.
+///
+/// The prefix is the key in the path, ending with a slash: `/users` and `/users/` are both the
+/// prefix `users/`. The root path is the empty prefix, which holds all keys.
+pub fn prefix<B>(req: &Request<B>) -> Option<String> {
+    key(req).map(|prefix| {
+        if prefix.is_empty() || prefix.ends_with('/') {
+            prefix
+        } else {
+            format!("{}/", prefix)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> Request<()> {
+        Request::get(path).body(()).unwrap()
+    }
+
+    #[test]
+    fn should_read_the_whole_path_as_the_key() {
+        assert_eq!(key(&request("/cat")), Some("cat".to_string()));
+        assert_eq!(key(&request("/tenant/entity/42")), Some("tenant/entity/42".to_string()));
+        assert_eq!(key(&request("/")), Some("".to_string()));
+        assert_eq!(key(&request("/cat?raw")), Some("cat".to_string()));
+    }
+
+    #[test]
+    fn should_percent_decode_the_key() {
+        assert_eq!(key(&request("/cat%20food")), Some("cat food".to_string()));
+        assert_eq!(key(&request("/a%2Fb")), Some("a/b".to_string()));
+        assert_eq!(key(&request("/%5Fkeys")), Some("_keys".to_string()));
+        assert_eq!(key(&request("/caf%C3%A9")), Some("café".to_string()));
+        assert_eq!(key(&request("/%FF")), None);
+    }
+
+    #[test]
+    fn should_end_the_prefix_with_a_slash() {
+        assert_eq!(prefix(&request("/users")), Some("users/".to_string()));
+        assert_eq!(prefix(&request("/users/")), Some("users/".to_string()));
+        assert_eq!(prefix(&request("/tenant%2F1")), Some("tenant/1/".to_string()));
+        assert_eq!(prefix(&request("/")), Some("".to_string()));
+        assert_eq!(prefix(&request("/%FF")), None);
+    }
+}
@@ -0,0 +1,67 @@
+//! Helpers for reading the query string of a request.
+
+use http::Request;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// The parsed query string of a request.
+///
+/// Parameters are URL-decoded. A parameter that is specified more than once holds its last
+/// value.
+#[derive(Debug, Default)]
+pub struct Query {
+    params: HashMap<String, String>,
+}
+
+impl Query {
+    /// Parses the query string of the specified request.
+    pub fn new<B>(req: &Request<B>) -> Self {
+        let params = req
+            .uri()
+            .query()
+            .map(|query| url::form_urlencoded::parse(query.as_bytes()).into_owned().collect())
+            .unwrap_or_default();
+
+        Query { params }
+    }
+
+    /// Returns the value of the specified parameter.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(|value| value.as_str())
+    }
+
+    /// Returns true if the specified parameter is set.
+    ///
+    /// A flag can be specified without a value (`?values`). It is not set if it is missing or
+    /// its value is `false` or `0`.
+    pub fn flag(&self, name: &str) -> bool {
+        match self.get(name) {
+            Some(value) => value != "false" && value != "0",
+            None => false,
+        }
+    }
+
+    /// Parses the value of the specified parameter.
+    ///
+    /// Returns `Ok(None)` if the parameter is missing and an error if it failed to parse.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        self.get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid value for '{}': {}", name, value))
+            })
+            .transpose()
+    }
+
+    /// Parses the `limit` parameter of a listing.
+    ///
+    /// A limit of 0 is rejected, since an empty page would tell the client that there are no
+    /// more keys.
+    pub fn limit(&self) -> Result<Option<usize>, String> {
+        match self.parse("limit")? {
+            Some(0) => Err("'limit' must be greater than 0".to_string()),
+            limit => Ok(limit),
+        }
+    }
+}
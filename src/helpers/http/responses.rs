@@ -12,6 +12,20 @@ impl Responses {
         Responses::response(StatusCode::OK, body)
     }
 
+    pub fn unauthorized(body: Option<Body>) -> Response<Body> {
+        Responses::response(
+            StatusCode::UNAUTHORIZED,
+            body.unwrap_or("unauthorized".into()),
+        )
+    }
+
+    pub fn forbidden(body: Option<Body>) -> Response<Body> {
+        Responses::response(
+            StatusCode::FORBIDDEN,
+            body.unwrap_or("forbidden".into()),
+        )
+    }
+
     pub fn not_found(body: Option<Body>) -> Response<Body> {
         Responses::response(StatusCode::NOT_FOUND, body.unwrap_or("not found".into()))
     }
@@ -27,6 +41,38 @@ impl Responses {
         Responses::response(StatusCode::NO_CONTENT, Body::empty())
     }
 
+    pub fn not_modified() -> Response<Body> {
+        Responses::response(StatusCode::NOT_MODIFIED, Body::empty())
+    }
+
+    pub fn precondition_failed(body: Option<Body>) -> Response<Body> {
+        Responses::response(
+            StatusCode::PRECONDITION_FAILED,
+            body.unwrap_or("precondition failed".into()),
+        )
+    }
+
+    pub fn unsupported_media_type(body: Option<Body>) -> Response<Body> {
+        Responses::response(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            body.unwrap_or("unsupported media type".into()),
+        )
+    }
+
+    pub fn payload_too_large(body: Option<Body>) -> Response<Body> {
+        Responses::response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            body.unwrap_or("payload too large".into()),
+        )
+    }
+
+    pub fn too_many_requests(body: Option<Body>) -> Response<Body> {
+        Responses::response(
+            StatusCode::TOO_MANY_REQUESTS,
+            body.unwrap_or("too many requests".into()),
+        )
+    }
+
     pub fn unprocessable(body: Option<Body>) -> Response<Body> {
         Responses::response(
             StatusCode::UNPROCESSABLE_ENTITY,
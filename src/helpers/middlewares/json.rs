@@ -18,7 +18,8 @@ where
             f(state, req).and_then(|mut response: Response<Body>| async {
                 response
                     .headers_mut()
-                    .insert("Content-Type", HeaderValue::from_static("application/json"));
+                    .entry("Content-Type")
+                    .or_insert(HeaderValue::from_static("application/json"));
 
                 Ok(response)
             })
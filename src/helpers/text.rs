@@ -0,0 +1,105 @@
+//! Helpers for the agents that speak line-based text protocols, like RESP and memcached.
+
+use std::error::Error as StdError;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+
+type Result<T> = std::result::Result<T, Box<dyn StdError + Send + Sync>>;
+
+/// Converts a JSON value of the state to a value of a text protocol.
+///
+/// JSON strings are returned verbatim and any other value is returned as JSON text.
+pub fn to_text(value: Vec<u8>) -> Vec<u8> {
+    match serde_json::from_slice(&value) {
+        Ok(serde_json::Value::String(s)) => s.into_bytes(),
+        _ => value,
+    }
+}
+
+/// Converts a value of a text protocol to a JSON value of the state.
+///
+/// The value is always stored as a JSON string, so it is returned exactly as it was set.
+pub fn from_text(value: &str) -> Vec<u8> {
+    serde_json::to_vec(value).unwrap_or_default()
+}
+
+/// Reads a single line and strips the trailing CRLF. Returns `None` at the end of the stream.
+///
+/// Fails if the line is longer than `max` bytes, without reading the rest of it.
+pub async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, max: usize) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if (&mut *reader).take(max as u64 + 2).read_until(b'\n', &mut line).await? == 0 {
+        return Ok(None);
+    }
+
+    if line.last() != Some(&b'\n') && line.len() > max {
+        return Err("line is too long".into());
+    }
+
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    Ok(Some(line))
+}
+
+/// Reads a block of `len` bytes that is followed by a CRLF, like a RESP bulk string or a memcached
+/// data block. Returns `None` if the block is not followed by a CRLF.
+///
+/// The block is read as it arrives, so a client cannot make the agent allocate more memory than
+/// it actually sends. Fails if the stream ends before the block does.
+pub async fn read_block<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, len: usize) -> Result<Option<Vec<u8>>> {
+    let mut block = Vec::with_capacity(len.min(64 * 1024) + 2);
+    (&mut *reader).take(len as u64 + 2).read_to_end(&mut block).await?;
+    if block.len() < len + 2 {
+        return Err("unexpected end of stream".into());
+    }
+
+    if !block.ends_with(b"\r\n") {
+        return Ok(None);
+    }
+
+    block.truncate(len);
+    Ok(Some(block))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_return_the_bytes_that_were_set() {
+        for &value in &["1.50", "1e2", "{ \"name\": \"garfield\" }", "\"quoted\"", "true", "null", "", "garfield"] {
+            let stored: serde_json::Value = serde_json::from_slice(&from_text(value)).unwrap();
+            assert_eq!(stored, serde_json::Value::String(value.to_string()));
+            assert_eq!(to_text(from_text(value)), value.as_bytes());
+        }
+    }
+
+    #[test]
+    fn should_return_values_of_other_agents_as_json_text() {
+        assert_eq!(to_text(b"\"garfield\"".to_vec()), b"garfield".to_vec());
+        assert_eq!(to_text(b"{\"name\":\"garfield\"}".to_vec()), b"{\"name\":\"garfield\"}".to_vec());
+        assert_eq!(to_text(b"1.50".to_vec()), b"1.50".to_vec());
+    }
+
+    #[tokio::test]
+    async fn should_bound_the_length_of_lines() {
+        let mut reader = BufReader::new(&b"garfield\r\ncat\n"[..]);
+        assert_eq!(read_line(&mut reader, 8).await.unwrap(), Some(b"garfield".to_vec()));
+        assert_eq!(read_line(&mut reader, 8).await.unwrap(), Some(b"cat".to_vec()));
+        assert_eq!(read_line(&mut reader, 8).await.unwrap(), None);
+
+        let mut reader = BufReader::new(&b"garfield!\r\n"[..]);
+        assert!(read_line(&mut reader, 8).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn should_read_blocks() {
+        let mut reader = BufReader::new(&b"garfield\r\ngarfield!!"[..]);
+        assert_eq!(read_block(&mut reader, 8).await.unwrap(), Some(b"garfield".to_vec()));
+        assert_eq!(read_block(&mut reader, 8).await.unwrap(), None);
+
+        let mut reader = BufReader::new(&b"garf"[..]);
+        assert!(read_block(&mut reader, 8).await.is_err());
+    }
+}
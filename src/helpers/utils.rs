@@ -4,7 +4,7 @@ use rand::rngs::SmallRng;
 use rand::seq::IteratorRandom;
 use rand::SeedableRng;
 use std::convert::TryFrom;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Returns the current time in seconds since epoch.
 pub fn epoch() -> u64 {
@@ -17,6 +17,23 @@ pub fn epoch() -> u64 {
     .unwrap_or(0)
 }
 
+/// Parses a duration such as `500ms`, `30s` or `5m`.
+///
+/// A number without a unit is parsed as milliseconds. Returns `None` if the duration is invalid or
+/// too long to represent.
+pub fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (value, unit) = s.split_at(s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len()));
+    let value: u64 = value.parse().ok()?;
+
+    match unit {
+        "" | "ms" => Some(Duration::from_millis(value)),
+        "s" => Some(Duration::from_secs(value)),
+        "m" => value.checked_mul(60).map(Duration::from_secs),
+        _ => None,
+    }
+}
+
 /// A trait to be implemented on iterators to allow conveniently sampling of set of random elements.
 pub trait Sample {
     type Item;
@@ -38,3 +55,19 @@ where
         self.choose_multiple(&mut rng, n)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_durations() {
+        assert_eq!(parse_duration("500"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration("500ms"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_duration(" 30s "), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("5h"), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration(&format!("{}m", u64::MAX)), None);
+    }
+}
@@ -20,8 +20,10 @@
 pub mod default;
 pub mod data_seeder;
 
+use serde::{Deserialize, Serialize};
 use std::error::Error as StdError;
 use std::sync::Arc;
+use tokio::sync::broadcast;
 
 /// An atomic reference to a state.
 ///
@@ -50,6 +52,112 @@ pub type SafeState = Arc<dyn State>;
 /// the user of incompatabilities.
 pub trait StateValue: Send + Sync {
     fn as_bytes(&self) -> Option<Vec<u8>>;
+
+    /// Returns the bytes of the value itself, without any metadata the state keeps along with it.
+    fn as_raw_bytes(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Returns the timestamp of the value, if the state keeps one.
+    ///
+    /// Layers can use the timestamp as a version of the value, for example to tell whether a
+    /// value has changed since it was last read.
+    fn ts(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the bytes of the value encoded in the specified format.
+    ///
+    /// The default implementation assumes `as_bytes` returns JSON and converts it. Implementors
+    /// can override it to encode the value directly.
+    fn encode(&self, format: Format) -> Option<Vec<u8>> {
+        format.encode(&self.as_bytes()?)
+    }
+}
+
+/// The format the values are encoded in when they are exchanged with the state.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// JSON, the format of [StateValue::as_bytes] for states that keep JSON values.
+    #[serde(rename = "json")]
+    Json,
+
+    /// [MessagePack](https://msgpack.org), which is smaller and faster to parse than JSON.
+    #[serde(rename = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    /// Encodes JSON bytes in this format.
+    pub fn encode(self, json: &[u8]) -> Option<Vec<u8>> {
+        match self {
+            Format::Json => Some(json.to_vec()),
+            Format::MessagePack => {
+                let value: serde_json::Value = serde_json::from_slice(json).ok()?;
+                rmp_serde::to_vec_named(&value).ok()
+            }
+        }
+    }
+
+    /// Decodes bytes in this format to JSON bytes.
+    pub fn decode(self, bytes: &[u8]) -> Result<Vec<u8>, Box<dyn StdError>> {
+        match self {
+            Format::Json => Ok(bytes.to_vec()),
+            Format::MessagePack => {
+                let value: serde_json::Value = rmp_serde::from_slice(bytes)?;
+                Ok(serde_json::to_vec(&value)?)
+            }
+        }
+    }
+}
+
+/// A change that was committed to the state.
+///
+/// States that support change notification send a `Change` to their subscribers whenever the
+/// value of a key actually changes.
+#[derive(Clone)]
+pub struct Change {
+    /// A sequential id of the change.
+    ///
+    /// Ids grow with every change that is committed to the state and can be used to resume
+    /// from the last change seen.
+    pub id: u64,
+
+    /// The kind of the change.
+    pub kind: ChangeKind,
+
+    /// The key that was changed.
+    pub key: String,
+
+    /// The timestamp of the change.
+    pub ts: u64,
+
+    /// The new value of the key or `None` if the key was removed from the state.
+    pub value: Option<Arc<dyn StateValue>>,
+}
+
+impl std::fmt::Debug for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Change")
+            .field("id", &self.id)
+            .field("kind", &self.kind)
+            .field("key", &self.key)
+            .field("ts", &self.ts)
+            .finish()
+    }
+}
+
+/// The kind of a [Change].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    /// The key was set to a new value.
+    Set,
+
+    /// The key was deleted.
+    Delete,
+
+    /// The key was expired.
+    Expire,
 }
 
 /// The State trait.
@@ -81,12 +189,199 @@ pub trait State: std::fmt::Debug + Send + Sync + CloneState {
     /// pairs where the key is a String and the value conforms to a serde_json::Value value.
     fn set(&self, value: &dyn StateValue) -> Result<(), Box<dyn StdError>>;
 
+    /// Sets a value that is encoded in the specified format.
+    ///
+    /// The default implementation converts the value to JSON and calls [set](State::set).
+    /// Implementors can override it to decode the value directly.
+    fn set_encoded(&self, value: &dyn StateValue, format: Format) -> Result<(), Box<dyn StdError>> {
+        match format {
+            Format::Json => self.set(value),
+            _ => self.set(&format.decode(&value.as_bytes().unwrap_or_default())?),
+        }
+    }
+
+    /// Sets a value to the state and waits until it is committed.
+    ///
+    /// Unlike `set`, which might commit the value in the background, this function returns once
+    /// the value was committed and reports back the result. The format of the result is up to the
+    /// implementor. The default state implementation, for example, reports whether the value of
+    /// each key was applied or ignored.
+    ///
+    /// The default implementation returns an error for states that do not support it.
+    fn set_sync(&self, _value: &dyn StateValue) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        Err("synchronous set is not supported by this state".into())
+    }
+
+    /// Sets the value of a single key.
+    ///
+    /// Unlike `set`, the value is the value itself, without any metadata the state keeps along
+    /// with it. An optional `ttl` in milliseconds can be specified.
+    ///
+    /// The default implementation returns an error for states that do not support it.
+    fn insert(
+        &self,
+        _key: &dyn StateValue,
+        _value: &dyn StateValue,
+        _ttl: Option<u64>,
+    ) -> Result<(), Box<dyn StdError>> {
+        Err("insert is not supported by this state".into())
+    }
+
+    /// Sets the value of a single key only if the [Condition] holds.
+    ///
+    /// This allows optimistic concurrency on the local state, for example setting a value only if
+    /// its current timestamp did not change. Returns the timestamp of the new value or `None` if
+    /// the condition did not hold and nothing was set.
+    ///
+    /// The default implementation returns an error for states that do not support it.
+    fn insert_if(
+        &self,
+        _key: &dyn StateValue,
+        _value: &dyn StateValue,
+        _ttl: Option<u64>,
+        _condition: Condition,
+    ) -> Result<Option<u64>, Box<dyn StdError>> {
+        Err("conditional insert is not supported by this state".into())
+    }
+
+    /// Applies a patch to the value of a single key.
+    ///
+    /// The patch is applied to the current local value and the result is committed as a new value,
+    /// the same as any other write. Returns the new value or `None` if the key does not exist.
+    ///
+    /// The default implementation returns an error for states that do not support patching.
+    fn patch(
+        &self,
+        _key: &dyn StateValue,
+        _patch: &dyn StateValue,
+        _kind: Patch,
+    ) -> Result<Option<Box<dyn StateValue>>, Box<dyn StdError>> {
+        Err("patch is not supported by this state".into())
+    }
+
+    /// Deletes the value associated with the specified key.
+    ///
+    /// How a delete is carried out is up to the implementor. The default state implementation,
+    /// for example, writes a tombstone so the delete can be exchanged with other peers.
+    ///
+    /// The default implementation returns an error for states that do not support deletes.
+    fn delete(&self, _key: &dyn StateValue) -> Result<(), Box<dyn StdError>> {
+        Err("delete is not supported by this state".into())
+    }
+
+    /// Deletes the value of a single key only if the [Condition] holds.
+    ///
+    /// Returns the timestamp of the delete or `None` if the condition did not hold and nothing
+    /// was deleted.
+    ///
+    /// The default implementation returns an error for states that do not support it.
+    fn delete_if(&self, _key: &dyn StateValue, _condition: Condition) -> Result<Option<u64>, Box<dyn StdError>> {
+        Err("conditional delete is not supported by this state".into())
+    }
+
     /// Gets the value associated with the specified key.
     ///
     /// To allow maximum flexibility, the key itself is a StateValue, which in effect means it can
     /// be anything desired by the implementor.
     fn get(&self, key: &dyn StateValue) -> Option<Box<dyn StateValue>>;
 
+    /// Gets the values associated with a batch of keys.
+    ///
+    /// As with `get`, the keys are a StateValue which is up to the implementor to interpret. All
+    /// values should be taken from the same snapshot of the state so the batch is consistent.
+    ///
+    /// The default implementation returns an error for states that do not support batch reads.
+    fn get_many(&self, _keys: &dyn StateValue) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        Err("batch get is not supported by this state".into())
+    }
+
+    /// Scans the keys of the state.
+    ///
+    /// Returns the keys (and optionally the values) that match the [Scan] options. The format of
+    /// the returned StateValue is up to the implementor, but it should carry a cursor that can be
+    /// used to continue the scan.
+    ///
+    /// The default implementation returns an error for states that do not support scanning.
+    fn scan(&self, _scan: &Scan) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        Err("scan is not supported by this state".into())
+    }
+
+    /// Returns the values whose indexed field equals `value`, using the index with the specified
+    /// name.
+    ///
+    /// `value` is expected in the same format as the values of the state. The values are returned
+    /// in the same form as a [scan](State::scan) with values, and `prefix`, `limit`, `cursor` and
+    /// `within` of `scan` apply to the matching values.
+    ///
+    /// The default implementation returns an error for states that do not support indexes.
+    fn lookup(&self, _index: &str, _value: &dyn StateValue, _scan: &Scan) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        Err("indexes are not supported by this state".into())
+    }
+
+    /// Returns the values under the prefix of `scan` that match a predicate.
+    ///
+    /// The language of the predicate is up to the implementor. The values are returned in the
+    /// same form as a [scan](State::scan) with values, and `limit` and `cursor` apply to the
+    /// matching values.
+    ///
+    /// The default implementation returns an error for states that do not support queries.
+    fn query(&self, _scan: &Scan, _predicate: &str) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        Err("query is not supported by this state".into())
+    }
+
+    /// Exports all the values of the state as records.
+    ///
+    /// The records are produced lazily from a single snapshot of the state, so the caller can
+    /// stream them without holding the whole state in memory in its serialized form. The format
+    /// of the records is up to the implementor, but it should be accepted by
+    /// [import](State::import).
+    ///
+    /// The default implementation returns an error for states that do not support exporting.
+    fn export(&self) -> Result<Records, Box<dyn StdError>> {
+        Err("export is not supported by this state".into())
+    }
+
+    /// Imports a batch of newline-delimited records, like the ones produced by
+    /// [export](State::export).
+    ///
+    /// The records are merged into the state like any other values, so a record that is older
+    /// than the current value is ignored. Nothing is imported if any of the records is malformed.
+    ///
+    /// The default implementation returns an error for states that do not support importing.
+    fn import(&self, _records: &dyn StateValue) -> Result<Import, Box<dyn StdError>> {
+        Err("import is not supported by this state".into())
+    }
+
+    /// Subscribes to changes that are committed to the state.
+    ///
+    /// Returns a receiver that gets a [Change] for every key that is changed after subscribing.
+    /// A receiver that falls behind misses the oldest changes, so subscribers should read the
+    /// state again when they lag.
+    ///
+    /// The default implementation returns `None` for states that do not notify about changes.
+    fn subscribe(&self) -> Option<broadcast::Receiver<Change>> {
+        None
+    }
+
+    /// Returns the changes that were committed after the change with the specified id.
+    ///
+    /// This allows a subscriber to resume from the last change it has seen. Returns `None` if
+    /// the state no longer keeps all of the changes since `id` or if it does not know the id.
+    ///
+    /// The default implementation returns `None` for states that do not keep a history of changes.
+    fn changes_since(&self, _id: u64) -> Option<Vec<Change>> {
+        None
+    }
+
+    /// Returns the id of the last change that was committed to the state, or `0` if there was none.
+    ///
+    /// A subscriber that reads the state can use it to know which changes its read already covers.
+    ///
+    /// The default implementation returns `None` for states that do not keep a history of changes.
+    fn last_change_id(&self) -> Option<u64> {
+        None
+    }
+
     /// Returns the value associated with the specified key or the default if the key was not found 
     /// in the state.
     fn get_or(&self, key: &dyn StateValue, default: Box<dyn StateValue>) -> Box<dyn StateValue> {
@@ -102,6 +397,85 @@ pub trait State: std::fmt::Debug + Send + Sync + CloneState {
     fn get_root(&self) -> Option<Box<dyn StateValue>>;
 }
 
+/// Options for scanning the keys of a state.
+#[derive(Debug, Default, Clone)]
+pub struct Scan {
+    /// Only keys that start with this prefix are scanned.
+    pub prefix: String,
+
+    /// The maximum number of keys to return. All matching keys are returned if `None`. Must be
+    /// greater than 0.
+    pub limit: Option<usize>,
+
+    /// Continues a previous scan from this cursor.
+    pub cursor: Option<String>,
+
+    /// Returns the values along with the keys.
+    pub values: bool,
+
+    /// Only keys that start with one of these prefixes are scanned, on top of `prefix`. All keys
+    /// are scanned if `None`.
+    pub within: Option<Vec<String>>,
+}
+
+impl Scan {
+    /// Fails if the options of the scan are invalid.
+    pub fn check(&self) -> Result<(), Box<dyn StdError>> {
+        match self.limit {
+            Some(0) => Err("the limit of a scan must be greater than 0".into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns true if the key is within the prefixes of the scan and after its cursor.
+    pub fn includes(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix)
+            && !matches!(&self.cursor, Some(cursor) if key <= cursor.as_str())
+            && !matches!(&self.within, Some(within) if !within.iter().any(|p| key.starts_with(p.as_str())))
+    }
+}
+
+/// The records of an export.
+pub type Records = Box<dyn Iterator<Item = Box<dyn StateValue>> + Send>;
+
+/// The outcome of an import.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Import {
+    /// The number of records that were read.
+    pub records: usize,
+
+    /// The number of records that were applied, as opposed to ones that were older than the
+    /// current values or already expired.
+    pub applied: usize,
+}
+
+/// The condition of a conditional insert.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// The key exists, whatever its timestamp is.
+    Exists,
+
+    /// The key does not exist.
+    Missing,
+
+    /// The key exists and its timestamp is the specified one.
+    Ts(u64),
+
+    /// Always holds, whether the key exists or not. This makes a conditional write an
+    /// unconditional one that is still committed before it returns.
+    Always,
+}
+
+/// The kind of a patch to apply to a value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Patch {
+    /// A JSON Merge Patch ([RFC 7396](https://tools.ietf.org/html/rfc7396)).
+    Merge,
+
+    /// A JSON Patch ([RFC 6902](https://tools.ietf.org/html/rfc6902)).
+    Json,
+}
+
 pub trait CloneState {
     fn clone_state(&self) -> Box<dyn State>;
 }
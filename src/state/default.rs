@@ -39,12 +39,31 @@
 //!
 //! The purger thread uses the interval settings from the configuration of the state.
 //!
+//! # Deletes
+//! A deleted key is not removed from the storage right away. Instead, a tombstone (a value
+//! marked as `deleted`) is written with the timestamp of the delete. The tombstone is exchanged
+//! with other peers like any other value, so a delete overrides older writes of the same key
+//! across the system.
+//!
+//! The state returns `None` for deleted keys. Tombstones are purged by the purger thread once
+//! `tombstone_ttl` milliseconds have passed since the delete.
+//!
 //! # Conflicts
 //! Since this is a distributed system, the state might be updated by different peers that are not
 //! yet in sync. To resolve a conflict where a key is being updated by more than one peer, a
 //! timestamp is used. Only a newer key can override an older one. The timestamp should be the
 //! timestamp when the key was first created (by the source).
 //!
+//! When a tombstone and a value share the same timestamp, the tombstone wins.
+//!
+//! # Indexes
+//! Fields inside the values can be indexed to look up keys by their values without scanning the
+//! whole state. See the [index] module.
+//!
+//! # Webhooks
+//! The changes of keys under configured prefixes, including the keys that expire, can be pushed
+//! to an HTTP endpoint in batches. See the [webhook] module.
+//!
 //! # Version History
 //! The state records version history for every change that is made to the state.
 //! To make sure the version history doesn't get bloated it is being purged on every 
@@ -52,18 +71,25 @@
 //!
 //! See the [struct@Default] state struct for details on the different fields and configurations. 
 
+pub mod index;
+pub mod predicate;
+pub mod webhook;
+
 use crate::helpers::utils::epoch;
 use crate::state::{self, data_seeder::DataSeeder};
 use crate::state::StateValue;
 use im::hashmap::HashMap;
+use predicate::Predicate;
 use serde::{Deserialize, Serialize};
 use serde_json;
+use std::collections::{BTreeMap, VecDeque};
 use std::error::Error as StdError;
 use std::sync::{Arc, RwLock};
 use tokio::time::{interval_at, Duration, Instant};
 use log::{info, warn};
 use std::sync::mpsc;
 use std::hash::{Hash, Hasher};
+use tokio::sync::broadcast;
 use twox_hash::XxHash64;
 
 /// The maximum number of pending async set operations.
@@ -73,6 +99,13 @@ use twox_hash::XxHash64;
 /// dealt with.
 const MAX_SET_OPS: usize = 64000;
 
+/// The maximum number of changes kept for subscribers.
+///
+/// A subscriber that falls behind by more than this number of changes will miss the oldest
+/// ones. This is also the number of recent changes kept in the history for subscribers to 
+/// resume from.
+const MAX_CHANGES: usize = 1024;
+
 /// Version information.
 ///
 /// Holds the timestamp where the version was recorded and 
@@ -95,9 +128,30 @@ pub struct Default {
     /// Default value is 1 minute (60000 milliseconds).
     purge_interval: u64,
 
+    /// The time in milliseconds to keep a tombstone of a deleted key before purging it.
+    ///
+    /// This should be long enough for the delete to spread to all peers, otherwise a peer that
+    /// did not get the delete might bring the key back to life.
+    /// Default value is 1 hour (3600000 milliseconds).
+    tombstone_ttl: u64,
+
     /// The [DataSeeder] to use for seeding the data on initialization.
     data_seeder: Option<Arc<RwLock<Box<dyn DataSeeder>>>>,
 
+    /// Secondary indexes on fields inside the values. See the [index] module.
+    ///
+    /// Default value: none.
+    indexes: Vec<index::Index>,
+
+    /// Webhooks to push the changes of the state to. See the [webhook] module.
+    ///
+    /// Default value: none.
+    webhooks: Vec<webhook::Webhook>,
+
+    /// The entries of the secondary indexes.
+    #[serde(skip_serializing, skip_deserializing)]
+    entries: Arc<RwLock<index::Entries>>,
+
     /// The version of the current state.
     ///
     /// This is set to a random unique string on every state change.
@@ -110,12 +164,20 @@ pub struct Default {
     /// will pass the operation to an async handler which will then commit the 
     /// changes to the state.
     #[serde(skip_serializing, skip_deserializing)]
-    tx: Option<mpsc::SyncSender<Vec<u8>>>,
+    tx: Option<mpsc::SyncSender<HashMap<String, Box<Value>>>>,
 
     /// The data storage in the form of a Key/Value hashmap.
     #[serde(skip_serializing, skip_deserializing)]
     storage: Arc<RwLock<HashMap<String, Box<Value>>>>,
 
+    /// The channel to notify subscribers about changes to the state.
+    #[serde(skip_serializing, skip_deserializing)]
+    changes: broadcast::Sender<state::Change>,
+
+    /// The most recent changes and the id of the last change.
+    #[serde(skip_serializing, skip_deserializing)]
+    history: Arc<RwLock<(VecDeque<state::Change>, u64)>>,
+
     /// Calculating the version is a bit expensive so we use 
     /// the dirty flag to lazily calculate the verison on-demand.
     #[serde(skip_serializing, skip_deserializing)]
@@ -129,40 +191,149 @@ impl Default {
     /// if it has a newer timestamp.
     ///
     /// If there was a change to the sate, the version will be recorded 
-    /// in the version history.
-    fn set(&self, map: &HashMap<String, Box<Value>>) {
-        let map = map.clone();
+    /// in the version history and subscribers will be notified about 
+    /// every key that was changed.
+    ///
+    /// Returns the outcome of the merge for every key in the map.
+    fn set(&self, map: &HashMap<String, Box<Value>>) -> Report {
+        let mut storage = self.storage.write().unwrap();
+        self.merge(&mut storage, map.clone())
+    }
+
+    /// Merges the map into the storage while resolving conflicts.
+    ///
+    /// Expects the caller to hold the write lock of the storage. The secondary indexes are updated
+    /// for every key that is applied.
+    fn merge(&self, storage: &mut HashMap<String, Box<Value>>, map: HashMap<String, Box<Value>>) -> Report {
         let mut is_dirty = false;
+        let mut changes = Vec::new();
+        let mut report = Report::new();
+        let mut entries = self.entries.write().unwrap();
 
-        // merge the maps
-        let mut storage = self.storage.write().unwrap();
         for (key, mut right) in map {
             if right.is_expired() {
+                report.insert(key, Outcome::Expired);
                 continue;
             }
 
-            if self.ttl.is_some() && right.ttl.is_none() {
+            if self.ttl.is_some() && right.ttl.is_none() && !right.deleted {
                 right.ttl = self.ttl;
             }
 
-            storage.entry(key)
-                .and_modify(|v| {
-                    if v.ts < right.ts {
-                        *v = right.clone().into();
-                        is_dirty = true;
-                    }})
-            .or_insert({
-                is_dirty = true;
-                right.into()
-            }); 
+            if let Some(left) = storage.get(&key) {
+                if !right.supersedes(left) {
+                    report.insert(key, Outcome::Ignored);
+                    continue;
+                }
+            }
+
+            report.insert(key.clone(), Outcome::Applied);
+            changes.push(right.change(&key));
+            entries.update(&self.indexes, &key, storage.get(&key).and_then(|left| left.indexed()), right.indexed());
+            storage.insert(key, right);
+            is_dirty = true;
+        }
+
+        if is_dirty {
+            *self.is_dirty.write().unwrap() = true;
+        }
+
+        // commit while holding the storage lock so change ids follow the order of the merges
+        self.commit(changes);
+
+        report
+    }
+
+    /// Returns a page of the keys that match the scan and the predicate, from a single snapshot of
+    /// the storage.
+    fn page(&self, scan: &state::Scan, predicate: Option<&Predicate>) -> Page {
+        let storage = self.storage.read().unwrap().clone();
+
+        let mut keys: Vec<&String> = storage
+            .iter()
+            .filter(|(k, v)| {
+                scan.includes(k)
+                    && !v.is_expired()
+                    && !v.deleted
+                    && !matches!(predicate, Some(predicate) if !predicate.matches(&v.entry(k)))
+            })
+            .map(|(k, _)| k)
+            .collect();
+        keys.sort();
+
+        let mut page = Page::default();
+        if let Some(limit) = scan.limit {
+            if keys.len() > limit {
+                keys.truncate(limit);
+                page.next = keys.last().map(|k| k.to_string());
+            }
+        }
+
+        if scan.values {
+            page.values = Some(keys.into_iter().map(|k| (k.clone(), storage[k].clone())).collect());
+        } else {
+            page.keys = Some(keys.into_iter().cloned().collect());
         }
 
-        *self.is_dirty.write().unwrap() = is_dirty;
+        page
     }
 
-    /// Purges expired keys.
+    /// Records the changes in the history, notifies the subscribers and queues the changes for
+    /// the webhooks.
+    ///
+    /// Every change is given the next sequential id. This is called both by merges and by the
+    /// purger.
+    fn commit(&self, changes: Vec<state::Change>) {
+        if changes.is_empty() {
+            return;
+        }
+
+        let mut history = self.history.write().unwrap();
+        for mut change in changes {
+            history.1 += 1;
+            change.id = history.1;
+
+            if history.0.len() == MAX_CHANGES {
+                history.0.pop_front();
+            }
+            history.0.push_back(change.clone());
+
+            for webhook in &self.webhooks {
+                webhook.push(&change);
+            }
+
+            // an error means there are no subscribers
+            let _ = self.changes.send(change);
+        }
+    }
+
+    /// Purges expired keys and tombstones that are older than `tombstone_ttl`.
+    ///
+    /// Subscribers are notified about every key that was expired, and the expired keys are
+    /// removed from the secondary indexes.
     fn purge(&self) {
-        self.storage.write().unwrap().retain(|_, v| !v.is_expired());
+        let tombstone_ttl = self.tombstone_ttl;
+        let mut changes = Vec::new();
+
+        let mut storage = self.storage.write().unwrap();
+        let mut entries = self.entries.write().unwrap();
+        storage.retain(|k, v| {
+            if v.is_expired() {
+                entries.update(&self.indexes, k, v.indexed(), None);
+                changes.push(state::Change {
+                    id: 0,
+                    kind: state::ChangeKind::Expire,
+                    key: k.clone(),
+                    ts: v.ts + v.ttl.unwrap_or_default(),
+                    value: None,
+                });
+                return false;
+            }
+
+            !v.is_dead_tombstone(tombstone_ttl)
+        });
+
+        self.commit(changes);
     }
 
     /// Seeds the state with the data from the DataSeeder.
@@ -183,15 +354,34 @@ impl std::default::Default for Default {
         Default {
             ttl: None,
             purge_interval: 60000,
+            tombstone_ttl: 3600000,
             version: Arc::new(RwLock::new(String::default())),
             storage: std::default::Default::default(),
             data_seeder: None,
             tx: None,
+            changes: broadcast::channel(MAX_CHANGES).0,
+            history: std::default::Default::default(),
             is_dirty: Arc::new(RwLock::new(false)),
+            indexes: Vec::new(),
+            webhooks: Vec::new(),
+            entries: std::default::Default::default(),
         }
     }
 }
 
+/// Returns true if the condition of a conditional write holds for the current value of a key.
+///
+/// Expired values and tombstones count as missing.
+fn holds(current: Option<&Value>, condition: state::Condition) -> bool {
+    let live = current.filter(|v| !v.is_expired() && !v.deleted).map(|v| v.ts);
+
+    match (live, condition) {
+        (_, state::Condition::Always) | (Some(_), state::Condition::Exists) | (None, state::Condition::Missing) => true,
+        (Some(live), state::Condition::Ts(ts)) => live == ts,
+        _ => false,
+    }
+}
+
 fn hash(hm: &HashMap<String, Box<Value>>) -> u64 {
     let mut h: u64 = 0;
 
@@ -199,6 +389,9 @@ fn hash(hm: &HashMap<String, Box<Value>>) -> u64 {
         let mut hasher = XxHash64::default();
         k.hash(&mut hasher);
         v.ts.hash(&mut hasher);
+        if v.deleted {
+            v.deleted.hash(&mut hasher);
+        }
         h ^= hasher.finish();
     }
 
@@ -226,9 +419,66 @@ struct Value {
 
     /// An optional TTL (resolved to an absolute epoch time) when this value will be expired.
     ttl: Option<u64>,
+
+    /// Marks this value as a tombstone of a deleted key.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
 }
 
 impl Value {
+    /// Returns a tombstone for a key deleted at the current time.
+    fn tombstone() -> Self {
+        Value {
+            value: serde_json::Value::Null,
+            ts: epoch(),
+            ttl: None,
+            deleted: true,
+        }
+    }
+
+    /// Returns true if this value should override the `other` value.
+    ///
+    /// A newer value always wins. A tombstone wins over a value with the same timestamp.
+    fn supersedes(&self, other: &Value) -> bool {
+        self.ts > other.ts || (self.ts == other.ts && self.deleted && !other.deleted)
+    }
+
+    /// Returns a change notification of setting this value to `key`.
+    ///
+    /// The id of the change is given when the change is committed.
+    fn change(&self, key: &str) -> state::Change {
+        state::Change {
+            id: 0,
+            kind: if self.deleted { state::ChangeKind::Delete } else { state::ChangeKind::Set },
+            key: key.to_string(),
+            ts: self.ts,
+            value: if self.deleted {
+                None
+            } else {
+                Some(Arc::new(self.clone()))
+            },
+        }
+    }
+
+    /// Returns true if this is a tombstone that is older than `tombstone_ttl`.
+    fn is_dead_tombstone(&self, tombstone_ttl: u64) -> bool {
+        self.deleted && self.ts + tombstone_ttl < epoch()
+    }
+
+    /// Returns the value to index, or `None` for a tombstone.
+    fn indexed(&self) -> Option<&serde_json::Value> {
+        if self.deleted {
+            None
+        } else {
+            Some(&self.value)
+        }
+    }
+
+    /// Returns the entry of `key` that predicates are evaluated against.
+    fn entry<'a>(&'a self, key: &'a str) -> predicate::Entry<'a> {
+        predicate::Entry { key, value: &self.value, ts: self.ts, ttl: self.ttl }
+    }
+
     /// Returns true if the value was expired.
     fn is_expired(&self) -> bool {
         match self.ttl {
@@ -238,16 +488,110 @@ impl Value {
     }
 }
 
+/// The outcome of merging a single key.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    /// The value was committed to the state.
+    Applied,
+
+    /// The value was ignored since the state holds a newer value.
+    Ignored,
+
+    /// The value was already expired when it arrived.
+    Expired,
+}
+
+/// The outcome of a merge for every key.
+type Report = BTreeMap<String, Outcome>;
+
+impl StateValue for Report {
+    fn as_bytes(&self) -> Option<Vec<u8>> {
+        serde_json::to_vec(self).ok()
+    }
+}
+
+/// The result of a batch get.
+///
+/// Holds the values that were found and the keys that were not.
+#[derive(Serialize, Debug, Default)]
+struct Batch {
+    found: HashMap<String, Box<Value>>,
+    missing: Vec<String>,
+}
+
+impl StateValue for Batch {
+    fn as_bytes(&self) -> Option<Vec<u8>> {
+        serde_json::to_vec(self).ok()
+    }
+}
+
+/// A page of scanned keys.
+///
+/// Holds either the keys or the keys and values of the page, and the cursor to continue the scan
+/// from.
+#[derive(Serialize, Debug, Default)]
+struct Page {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keys: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    values: Option<BTreeMap<String, Box<Value>>>,
+
+    next: Option<String>,
+}
+
+impl StateValue for Page {
+    fn as_bytes(&self) -> Option<Vec<u8>> {
+        serde_json::to_vec(self).ok()
+    }
+}
+
 impl StateValue for Value {
     fn as_bytes(&self) -> Option<Vec<u8>> {
         serde_json::to_vec(self).ok()
     }
+
+    fn as_raw_bytes(&self) -> Option<Vec<u8>> {
+        serde_json::to_vec(&self.value).ok()
+    }
+
+    fn ts(&self) -> Option<u64> {
+        Some(self.ts)
+    }
 }
 
 impl StateValue for HashMap<String, Box<Value>> {
     fn as_bytes(&self) -> Option<Vec<u8>> {
         serde_json::to_vec(self).ok()
     }
+
+    /// Encodes the map directly, since it can hold the whole state.
+    fn encode(&self, format: state::Format) -> Option<Vec<u8>> {
+        match format {
+            state::Format::Json => self.as_bytes(),
+            state::Format::MessagePack => rmp_serde::to_vec_named(self).ok(),
+        }
+    }
+}
+
+/// A key and its value, as exported and imported.
+#[derive(Serialize, Deserialize, Debug)]
+struct Record {
+    key: String,
+    value: serde_json::Value,
+
+    #[serde(default = "epoch")]
+    ts: u64,
+
+    #[serde(default)]
+    ttl: Option<u64>,
+}
+
+impl StateValue for Record {
+    fn as_bytes(&self) -> Option<Vec<u8>> {
+        serde_json::to_vec(self).ok()
+    }
 }
 
 impl StateValue for (String, Value) {
@@ -264,11 +608,25 @@ impl state::State for Default {
     ///
     /// Spawns an async set thread which will perform async commits to the state.
     ///
+    /// Spawns a task for every webhook to send the changes of the state to.
+    ///
     /// Spawns a new thread to purge expired values and versions at a certain interval and returns a safe state
     /// to be shared with the connection and agent layers.
     fn init(&self) -> state::SafeState {
         let mut this = self.clone();
 
+        for index in this.indexes.iter().filter(|index| !index.pointer.is_empty() && !index.pointer.starts_with('/')) {
+            warn!("The pointer {} of index {} does not start with '/' and will not match any value", index.pointer, index.name);
+        }
+
+        this.webhooks.retain(|webhook| {
+            if webhook.url().is_empty() {
+                warn!("A webhook without a url is ignored");
+            }
+
+            !webhook.url().is_empty()
+        });
+
         // if we have a data seeder then use it to seed the data
         this.data_seeder.clone().and_then(|data_seeder| {
             info!("Seeding data...");
@@ -290,6 +648,11 @@ impl state::State for Default {
         // start the purger thread
         tokio::spawn(purge(this.clone()));
 
+        // start sending the changes to the webhooks
+        for webhook in this.webhooks.iter() {
+            tokio::spawn(webhook.clone().run());
+        }
+
         this
     }
 
@@ -309,14 +672,167 @@ impl state::State for Default {
     /// The value (hashmap) is then filtered to include only values that are new or newer than the current
     /// values in store. This is to resolve conflicts of updating items that were already updated
     /// by another peer. See the module documentation for more information on conflict resolution.
+    ///
+    /// The value is parsed before it is passed to the async set thread, so a malformed value is
+    /// reported back to the caller.
     fn set(&self, value: &dyn StateValue) -> Result<(), Box<dyn StdError>> {
-        value.as_bytes().and_then(|value| {
-            self.tx.as_ref().and_then(|tx| tx.send(value).ok())
-        });
+        self.set_encoded(value, state::Format::Json)
+    }
+
+    /// Sets a new value that is encoded in the specified format.
+    ///
+    /// The value is decoded straight into the key/value hashmap, without converting it to JSON
+    /// first, and is then handled the same as with `set`.
+    fn set_encoded(&self, value: &dyn StateValue, format: state::Format) -> Result<(), Box<dyn StdError>> {
+        let value = match format {
+            state::Format::Json => {
+                let value: Result<HashMap<String, Box<Value>>, Box<dyn StdError>> = value.into();
+                value?
+            }
+            state::Format::MessagePack => rmp_serde::from_slice(&value.as_bytes().unwrap_or_default())?,
+        };
+
+        if let Some(tx) = self.tx.as_ref() {
+            tx.send(value)?;
+        }
 
         Ok(())
     }
 
+    /// Sets a new value to the state and waits until it is merged.
+    ///
+    /// The value is expected in the same form as with `set`. Returns a JSON object with the
+    /// outcome of the merge for every key:
+    ///
+    /// ```json
+    /// {"cat": "applied", "dog": "ignored", "mouse": "expired"}
+    /// ```
+    ///
+    /// `applied` means the value was committed, `ignored` that the state already holds a newer value
+    /// and `expired` that the value was already expired when it arrived.
+    fn set_sync(&self, value: &dyn StateValue) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        let value: Result<HashMap<String, Box<Value>>, Box<dyn StdError>> = value.into();
+        let report = Default::set(self, &value?);
+
+        Ok(Box::new(report))
+    }
+
+    /// Sets the value of a single key.
+    ///
+    /// `key` is expected to resolve to a string and `value` to be any JSON value. The value is
+    /// given the current timestamp and committed the same way as with `set`.
+    fn insert(&self, key: &dyn StateValue, value: &dyn StateValue, ttl: Option<u64>) -> Result<(), Box<dyn StdError>> {
+        let key = String::from_utf8(key.as_bytes().unwrap_or_default())?;
+        let value = Value {
+            value: serde_json::from_slice(&value.as_bytes().unwrap_or_default())?,
+            ts: epoch(),
+            ttl,
+            deleted: false,
+        };
+
+        state::State::set(self, &HashMap::unit(key, Box::new(value)))
+    }
+
+    /// Sets the value of a single key if the condition holds.
+    ///
+    /// The check and the write are done while holding the write lock of the storage, so no other
+    /// write can get in between. The new value is given the current timestamp, or a timestamp
+    /// right after the current one if the clock is behind, so it always overrides the current value
+    /// (including a tombstone).
+    fn insert_if(
+        &self,
+        key: &dyn StateValue,
+        value: &dyn StateValue,
+        ttl: Option<u64>,
+        condition: state::Condition,
+    ) -> Result<Option<u64>, Box<dyn StdError>> {
+        let key = String::from_utf8(key.as_bytes().unwrap_or_default())?;
+        let value: serde_json::Value = serde_json::from_slice(&value.as_bytes().unwrap_or_default())?;
+
+        let mut storage = self.storage.write().unwrap();
+        let current = storage.get(&key);
+        if !holds(current.map(|v| &**v), condition) {
+            return Ok(None);
+        }
+
+        let value = Value { value, ts: epoch().max(current.map_or(0, |v| v.ts + 1)), ttl, deleted: false };
+        let ts = value.ts;
+        self.merge(&mut storage, HashMap::unit(key, Box::new(value)));
+
+        Ok(Some(ts))
+    }
+
+    /// Applies a patch to the value of a single key.
+    ///
+    /// `key` is expected to resolve to a string and `patch` to be a JSON Merge Patch or a JSON
+    /// Patch document, depending on `kind`. The patch is applied while holding the write lock of the
+    /// storage, so no other write can get in between.
+    ///
+    /// The patched value is given the current timestamp (or a timestamp right after the current
+    /// one if the clock is behind) and keeps the TTL of the current value. If the patch fails, for
+    /// example when a `test` operation does not match, nothing is committed.
+    fn patch(
+        &self,
+        key: &dyn StateValue,
+        patch: &dyn StateValue,
+        kind: state::Patch,
+    ) -> Result<Option<Box<dyn StateValue>>, Box<dyn StdError>> {
+        let key = String::from_utf8(key.as_bytes().unwrap_or_default())?;
+        let patch: serde_json::Value = serde_json::from_slice(&patch.as_bytes().unwrap_or_default())?;
+
+        let mut storage = self.storage.write().unwrap();
+        let mut value = match storage.get(&key).filter(|v| !v.is_expired() && !v.deleted) {
+            Some(value) => value.clone(),
+            None => return Ok(None),
+        };
+
+        match kind {
+            state::Patch::Merge => json_patch::merge(&mut value.value, &patch),
+            state::Patch::Json => {
+                let patch: json_patch::Patch = serde_json::from_value(patch)?;
+                json_patch::patch(&mut value.value, &patch)?;
+            }
+        }
+
+        value.ts = epoch().max(value.ts + 1);
+        self.merge(&mut storage, HashMap::unit(key, value.clone()));
+
+        Ok(Some(value.into()))
+    }
+
+    /// Deletes the value associated with the specified key.
+    ///
+    /// `key` is expected to resolve to a string. A tombstone with the current timestamp is
+    /// committed to the state the same way a new value is, so it goes through the same conflict
+    /// resolution and is exchanged with other peers.
+    fn delete(&self, key: &dyn StateValue) -> Result<(), Box<dyn StdError>> {
+        let key = String::from_utf8(key.as_bytes().unwrap_or_default())?;
+        let tombstone: HashMap<String, Box<Value>> = HashMap::unit(key, Value::tombstone().into());
+
+        state::State::set(self, &tombstone)
+    }
+
+    /// Deletes the value of a single key if the condition holds.
+    ///
+    /// The check and the write of the tombstone are done while holding the write lock of the
+    /// storage, like [insert_if](#method.insert_if). The tombstone is given the current timestamp,
+    /// or a timestamp right after the current one if the clock is behind.
+    fn delete_if(&self, key: &dyn StateValue, condition: state::Condition) -> Result<Option<u64>, Box<dyn StdError>> {
+        let key = String::from_utf8(key.as_bytes().unwrap_or_default())?;
+
+        let mut storage = self.storage.write().unwrap();
+        let current = storage.get(&key);
+        if !holds(current.map(|v| &**v), condition) {
+            return Ok(None);
+        }
+
+        let tombstone = Value { ts: epoch().max(current.map_or(0, |v| v.ts + 1)), ..Value::tombstone() };
+        let ts = tombstone.ts;
+        self.merge(&mut storage, HashMap::unit(key, Box::new(tombstone)));
+
+        Ok(Some(ts))
+    }
+
     /// Returns the value associated with the specified key.
     ///
     /// `key` is expected to resolve to a string.
@@ -327,10 +843,189 @@ impl state::State for Default {
         storage
             .get(&key)
             .cloned()
-            .filter(|v| !v.is_expired())
+            .filter(|v| !v.is_expired() && !v.deleted)
             .map(|v| v.into())
     }
 
+    /// Returns the values associated with a batch of keys.
+    ///
+    /// `keys` is expected to be a JSON array of strings. All values are read from the same
+    /// snapshot of the storage. Returns a JSON object of the following form:
+    ///
+    /// ```json
+    /// {"found": {"cat": {"value": "garfield", "ts": 1601241450390, "ttl": null}}, "missing": ["dog"]}
+    /// ```
+    ///
+    /// Expired and deleted keys are reported as missing.
+    fn get_many(&self, keys: &dyn StateValue) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        let keys: Vec<String> = serde_json::from_slice(&keys.as_bytes().unwrap_or_default())?;
+
+        let storage = self.storage.read().unwrap().clone();
+        let mut batch = Batch::default();
+        for key in keys {
+            match storage.get(&key).filter(|v| !v.is_expired() && !v.deleted) {
+                Some(value) => { batch.found.insert(key, value.clone()); },
+                None => batch.missing.push(key),
+            }
+        }
+
+        Ok(Box::new(batch))
+    }
+
+    /// Scans the keys that start with the prefix of `scan`.
+    ///
+    /// Keys are scanned in lexicographical order from a single snapshot of the storage. Expired
+    /// and deleted keys are skipped, and so are keys outside the `within` prefixes. Returns a JSON
+    /// object of the following form:
+    ///
+    /// ```json
+    /// {"keys": ["cat", "dog"], "next": "dog"}
+    /// ```
+    ///
+    /// When values are requested, `keys` is replaced with a `values` object that maps each key to
+    /// its value. `next` is the cursor to continue the scan from or `null` if there are no more
+    /// keys to scan.
+    fn scan(&self, scan: &state::Scan) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        scan.check()?;
+        Ok(Box::new(self.page(scan, None)))
+    }
+
+    /// Returns the values whose indexed field equals `value`, using the index with the specified
+    /// name.
+    ///
+    /// `value` is any JSON value. The keys are read from the index and their values from the
+    /// storage while both are locked, so the result is consistent. Expired and deleted keys are
+    /// skipped. Returns the values in the same form as a scan with values, or an error if there is
+    /// no such index.
+    fn lookup(&self, index: &str, value: &dyn StateValue, scan: &state::Scan) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        scan.check()?;
+        let value: serde_json::Value = serde_json::from_slice(&value.as_bytes().unwrap_or_default())?;
+
+        let storage = self.storage.read().unwrap();
+        let entries = self.entries.read().unwrap();
+        let keys = entries
+            .lookup(&self.indexes, index, &value)
+            .ok_or_else(|| format!("there is no index named {}", index))?;
+
+        let mut page = Page::default();
+        let mut values = BTreeMap::new();
+        for key in keys.filter(|key| scan.includes(key)) {
+            let value = match storage.get(key).filter(|v| !v.is_expired() && !v.deleted) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if matches!(scan.limit, Some(limit) if values.len() == limit) {
+                page.next = values.keys().next_back().cloned();
+                break;
+            }
+
+            values.insert(key.clone(), value.clone());
+        }
+
+        page.values = Some(values);
+        Ok(Box::new(page))
+    }
+
+    /// Returns the values under the prefix of `scan` that match a predicate.
+    ///
+    /// The predicate is evaluated against every value of a single snapshot of the storage. See the
+    /// [predicate] module for its syntax, for example:
+    ///
+    /// ```text
+    /// value.plan == "pro" && value.active
+    /// ```
+    ///
+    /// Returns the values in the same form as a scan with values.
+    fn query(&self, scan: &state::Scan, predicate: &str) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
+        scan.check()?;
+        let predicate = Predicate::parse(predicate)?;
+        let scan = state::Scan { values: true, ..scan.clone() };
+
+        Ok(Box::new(self.page(&scan, Some(&predicate))))
+    }
+
+    /// Exports the live values of the state.
+    ///
+    /// The records are read from a single snapshot of the storage, in no particular order, and
+    /// are serialized one at a time. Expired and deleted keys are skipped. Every record is a JSON
+    /// object of the following form:
+    ///
+    /// ```json
+    /// {"key": "cat", "value": "garfield", "ts": 1601241450390, "ttl": null}
+    /// ```
+    fn export(&self) -> Result<state::Records, Box<dyn StdError>> {
+        let storage = self.storage.read().unwrap().clone();
+        let records = storage
+            .into_iter()
+            .filter(|(_, v)| !v.is_expired() && !v.deleted)
+            .map(|(key, v)| Box::new(Record { key, value: v.value, ts: v.ts, ttl: v.ttl }) as Box<dyn StateValue>);
+
+        Ok(Box::new(records))
+    }
+
+    /// Imports a batch of newline-delimited records of the form produced by `export`.
+    ///
+    /// `ts` is optional and defaults to the current time. The records are merged the same way as
+    /// with `set`, and the merge is waited for. Empty lines are skipped.
+    fn import(&self, records: &dyn StateValue) -> Result<state::Import, Box<dyn StdError>> {
+        let records = records.as_bytes().unwrap_or_default();
+
+        let mut map: HashMap<String, Box<Value>> = HashMap::new();
+        let mut import = state::Import::default();
+        for (i, line) in records.split(|b| *b == b'\n').enumerate() {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+
+            let record: Record = serde_json::from_slice(line).map_err(|e| format!("record {}: {}", i + 1, e))?;
+            let value = Value { value: record.value, ts: record.ts, ttl: record.ttl, deleted: false };
+            let newer = match map.get(&record.key) {
+                Some(current) => value.supersedes(current),
+                None => true,
+            };
+
+            if newer {
+                map.insert(record.key, Box::new(value));
+            }
+            import.records += 1;
+        }
+
+        let report = Default::set(self, &map);
+        import.applied = report.values().filter(|outcome| **outcome == Outcome::Applied).count();
+
+        Ok(import)
+    }
+
+    /// Subscribes to changes of the state.
+    ///
+    /// A change is sent for every key that is changed by a merge. A tombstone is sent as a
+    /// change without a value.
+    fn subscribe(&self) -> Option<broadcast::Receiver<state::Change>> {
+        Some(self.changes.subscribe())
+    }
+
+    /// Returns the changes committed after the change with the specified id.
+    ///
+    /// Only the most recent changes are kept, so `None` is returned if some of the changes since
+    /// `id` were already dropped from the history or if `id` is newer than the last change.
+    fn changes_since(&self, id: u64) -> Option<Vec<state::Change>> {
+        let history = self.history.read().unwrap();
+        let (changes, last) = &*history;
+        let first = changes.front().map(|change| change.id).unwrap_or(last + 1);
+
+        if id > *last || id + 1 < first {
+            return None;
+        }
+
+        Some(changes.iter().filter(|change| change.id > id).cloned().collect())
+    }
+
+    /// Returns the id of the last change committed to the state.
+    fn last_change_id(&self) -> Option<u64> {
+        Some(self.history.read().unwrap().1)
+    }
+
     /// Returns the whole state (root).
     fn get_root(&self) -> Option<Box<dyn StateValue>> {
         let value: HashMap<String, Box<Value>> = self.storage.read().unwrap().clone();
@@ -342,15 +1037,17 @@ impl state::State for Default {
     /// If a key is present in both the current state and `other`, it will check if 
     /// the timestamps are equal and if not then it'll include either the current value or 
     /// the one from `other`, based on who's value has the most recent timestamp.
+    ///
+    /// Tombstones are included in the diff so deletes are exchanged like any other change.
     fn diff(&self, other: &dyn StateValue) -> Result<Box<dyn StateValue>, Box<dyn StdError>> {
         let other: Result<HashMap<String, Box<Value>>, Box<dyn StdError>> = other.into();
         let other = other?;
 
         let d = self.storage.read().unwrap().clone().difference_with(other, |left, right| {
-            if left.ts == right.ts {
+            if left.ts == right.ts && left.deleted == right.deleted {
                 None
             } else {
-                Some(if left.ts < right.ts { left } else { right })
+                Some(if right.supersedes(&left) { right } else { left })
             }
         });
 
@@ -376,12 +1073,9 @@ fn purge(state: Arc<Default>) -> impl futures::future::Future<Output = ()> + Sen
 /// Async set thread.
 ///
 /// Listens on the receiver channel for values to be commited to the state.
-fn async_set(state: Arc<Default>, rx: mpsc::Receiver<Vec<u8>>) {
+fn async_set(state: Arc<Default>, rx: mpsc::Receiver<HashMap<String, Box<Value>>>) {
     for value in rx.iter() {
-        let value = serde_json::from_slice(value.as_slice());
-        if let Ok(value) = value {
-            Default::set(&state, &value);
-        }
+        Default::set(&state, &value);
     }
 }
 
@@ -408,7 +1102,7 @@ mod tests {
 
     #[test]
     fn state_versions_should_be_equal() {
-        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None}.into());
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
 
         let first = Default::default();
         let second = Default::default();
@@ -421,8 +1115,8 @@ mod tests {
 
     #[test]
     fn state_versions_should_be_different() {
-        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None}.into());
-        let value2 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 1, ttl: None}.into());
+        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
+        let value2 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 1, ttl: None, deleted: false}.into());
 
         let first = Default::default();
         let second = Default::default();
@@ -435,12 +1129,12 @@ mod tests {
 
     #[test]
     fn should_purge_items() {
-        let value = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None}.into());
+        let value = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None, deleted: false}.into());
 
         let state = Default::default();
 
         // Force insersion of expired vlaue
-        state.storage.write().unwrap().insert("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: Some(1)}.into());
+        state.storage.write().unwrap().insert("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: Some(1), deleted: false}.into());
         state.set(&value);
 
         assert_eq!(state.storage.read().unwrap().len(), 2);
@@ -450,12 +1144,12 @@ mod tests {
 
     #[test]
     fn should_not_return_expired_values() {
-        let value = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None}.into());
+        let value = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None, deleted: false}.into());
 
         let state = Default::default();
 
         // Force insersion of expired vlaue
-        state.storage.write().unwrap().insert("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: Some(1)}.into());
+        state.storage.write().unwrap().insert("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: Some(1), deleted: false}.into());
         state.set(&value);
 
         assert!(state.get(&"dog".to_string() as &dyn StateValue).is_some());
@@ -464,7 +1158,7 @@ mod tests {
 
     #[test]
     fn should_be_marked_as_dirty() {
-        let value = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None}.into());
+        let value = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None, deleted: false}.into());
         let state = Default::default();
 
         assert_eq!(*state.is_dirty.read().unwrap(), false);
@@ -474,8 +1168,8 @@ mod tests {
 
     #[test]
     fn should_return_the_whole_state() {
-        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None}.into());
-        let value2 = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None}.into());
+        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
+        let value2 = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None, deleted: false}.into());
         let state = Default::default();
 
         state.set(&value1);
@@ -489,8 +1183,8 @@ mod tests {
 
     #[test]
     fn should_return_diff() {
-        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None}.into());
-        let value2 = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None}.into());
+        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
+        let value2 = HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: None, deleted: false}.into());
 
         let state = Default::default();
 
@@ -502,4 +1196,391 @@ mod tests {
 
         assert_eq!(vec!("cat"), keys);
     }
+
+    #[test]
+    fn tombstone_should_override_older_values() {
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
+        let tombstone = HashMap::unit("cat".to_string(), Value::tombstone().into());
+
+        let state = Default::default();
+
+        state.set(&value);
+        state.set(&tombstone);
+        state.set(&value);
+
+        assert!(state.get(&"cat".to_string() as &dyn StateValue).is_none());
+        assert!(state.storage.read().unwrap().get("cat").unwrap().deleted);
+    }
+
+    #[test]
+    fn tombstone_should_win_on_equal_timestamps() {
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 1, ttl: None, deleted: false}.into());
+        let tombstone = HashMap::unit("cat".to_string(), Value {value: serde_json::Value::Null, ts: 1, ttl: None, deleted: true}.into());
+
+        let first = Default::default();
+        let second = Default::default();
+
+        first.set(&value);
+        first.set(&tombstone);
+        second.set(&tombstone);
+        second.set(&value);
+
+        assert!(first.get(&"cat".to_string() as &dyn StateValue).is_none());
+        assert!(second.get(&"cat".to_string() as &dyn StateValue).is_none());
+        assert_eq!(first.version(), second.version());
+    }
+
+    #[test]
+    fn should_purge_old_tombstones() {
+        let tombstone = HashMap::unit("cat".to_string(), Value {value: serde_json::Value::Null, ts: 0, ttl: None, deleted: true}.into());
+        let state = Default::default();
+
+        state.set(&tombstone);
+        state.set(&HashMap::unit("dog".to_string(), Value::tombstone().into()));
+
+        assert_eq!(state.storage.read().unwrap().len(), 2);
+        state.purge();
+        assert_eq!(state.storage.read().unwrap().len(), 1);
+        assert!(state.storage.read().unwrap().contains_key("dog"));
+    }
+
+    #[test]
+    fn diff_should_include_newer_tombstones() {
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
+        let state = Default::default();
+
+        state.set(&value);
+        state.set(&HashMap::unit("cat".to_string(), Value::tombstone().into()));
+
+        let diff: Result<HashMap<String, Box<Value>>, Box<dyn StdError>> = (&*state.diff(&value).unwrap()).into();
+
+        assert!(diff.unwrap().get("cat").unwrap().deleted);
+    }
+
+    #[test]
+    fn should_return_found_and_missing_keys() {
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: None, deleted: false}.into());
+        let state = Default::default();
+
+        state.set(&value);
+        state.storage.write().unwrap().insert("dog".to_string(), Value {value: "snoopy".into(), ts: 0, ttl: Some(1), deleted: false}.into());
+
+        let batch = state.get_many(&r#"["cat", "dog", "mouse"]"# as &dyn StateValue).unwrap();
+        let batch: serde_json::Value = serde_json::from_slice(&batch.as_bytes().unwrap()).unwrap();
+
+        assert_eq!(batch["found"]["cat"]["value"], "garfield");
+        assert_eq!(batch["missing"], serde_json::json!(["dog", "mouse"]));
+    }
+
+    #[test]
+    fn should_scan_keys_by_prefix() {
+        let state = Default::default();
+        for key in &["cat/garfield", "cat/tom", "cat/felix", "dog/snoopy"] {
+            state.set(&HashMap::unit(key.to_string(), Value {value: "".into(), ts: 0, ttl: None, deleted: false}.into()));
+        }
+        state.storage.write().unwrap().insert("cat/sylvester".to_string(), Value {value: "".into(), ts: 0, ttl: Some(1), deleted: false}.into());
+
+        let scan = |cursor: Option<String>| {
+            let scan = state::Scan {prefix: "cat/".to_string(), limit: Some(2), cursor, values: false, within: None};
+            let page = state.scan(&scan).unwrap();
+            serde_json::from_slice::<serde_json::Value>(&page.as_bytes().unwrap()).unwrap()
+        };
+
+        let page = scan(None);
+        assert_eq!(page["keys"], serde_json::json!(["cat/felix", "cat/garfield"]));
+        assert_eq!(page["next"], "cat/garfield");
+
+        let page = scan(Some("cat/garfield".to_string()));
+        assert_eq!(page["keys"], serde_json::json!(["cat/tom"]));
+        assert!(page["next"].is_null());
+
+        let within = Some(vec!["cat/t".to_string(), "dog/".to_string()]);
+        let page = state.scan(&state::Scan {prefix: "".to_string(), limit: None, cursor: None, values: false, within}).unwrap();
+        let page = serde_json::from_slice::<serde_json::Value>(&page.as_bytes().unwrap()).unwrap();
+        assert_eq!(page["keys"], serde_json::json!(["cat/tom", "dog/snoopy"]));
+
+        let empty = state::Scan {prefix: "cat/".to_string(), limit: Some(0), cursor: None, values: false, within: None};
+        assert!(state.scan(&empty).is_err());
+        assert!(state.query(&empty, "true").is_err());
+    }
+
+    #[test]
+    fn should_notify_about_changed_keys_only() {
+        let value1 = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 1, ttl: None, deleted: false}.into());
+        let value2 = HashMap::unit("cat".to_string(), Value {value: "tom".into(), ts: 0, ttl: None, deleted: false}.into());
+        let state = Default::default();
+        let mut changes = state.subscribe().unwrap();
+
+        state.set(&value1);
+        state.set(&value2);
+        state.set(&HashMap::unit("cat".to_string(), Value::tombstone().into()));
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.key, "cat");
+        assert_eq!(change.ts, 1);
+        assert!(change.value.is_some());
+
+        let change = changes.try_recv().unwrap();
+        assert!(change.ts > 1);
+        assert!(change.value.is_none());
+        assert_eq!(change.kind, state::ChangeKind::Delete);
+
+        assert!(changes.try_recv().is_err());
+    }
+
+    #[test]
+    fn should_return_changes_since_id() {
+        let state = Default::default();
+        for key in &["cat", "dog", "mouse"] {
+            state.set(&HashMap::unit(key.to_string(), Value {value: "".into(), ts: 0, ttl: None, deleted: false}.into()));
+        }
+
+        let keys = |changes: Vec<state::Change>| changes.into_iter().map(|c| c.key).collect::<Vec<_>>();
+
+        assert_eq!(keys(state.changes_since(0).unwrap()), vec!["cat", "dog", "mouse"]);
+        assert_eq!(keys(state.changes_since(2).unwrap()), vec!["mouse"]);
+        assert!(state.changes_since(3).unwrap().is_empty());
+        assert!(state.changes_since(4).is_none());
+    }
+
+    #[test]
+    fn should_notify_about_expired_keys() {
+        let state = Default::default();
+        let mut changes = state.subscribe().unwrap();
+
+        state.storage.write().unwrap().insert("cat".to_string(), Value {value: "garfield".into(), ts: 0, ttl: Some(1), deleted: false}.into());
+        state.purge();
+
+        let change = changes.try_recv().unwrap();
+        assert_eq!(change.key, "cat");
+        assert_eq!(change.kind, state::ChangeKind::Expire);
+    }
+
+    #[test]
+    fn should_insert_only_if_timestamps_match() {
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 1, ttl: None, deleted: false}.into());
+        let state = Default::default();
+        state.set(&value);
+
+        let key = "cat".to_string();
+        let tom = r#""tom""#;
+
+        assert!(state.insert_if(&key, &tom, None, state::Condition::Ts(0)).unwrap().is_none());
+        let ts = state.insert_if(&key, &tom, None, state::Condition::Ts(1)).unwrap().unwrap();
+        assert!(ts > 1);
+        assert!(state.insert_if(&key, &tom, None, state::Condition::Ts(1)).unwrap().is_none());
+        assert!(state.insert_if(&key, &tom, None, state::Condition::Missing).unwrap().is_none());
+        assert!(state.insert_if(&"dog".to_string(), &tom, None, state::Condition::Exists).unwrap().is_none());
+        assert!(state.insert_if(&"dog".to_string(), &tom, None, state::Condition::Missing).unwrap().is_some());
+
+        assert_eq!(state.get(&key as &dyn StateValue).unwrap().ts(), Some(ts));
+
+        let always = state.insert_if(&key, &tom, None, state::Condition::Always).unwrap().unwrap();
+        assert!(always > ts);
+        assert!(state.insert_if(&"mouse".to_string(), &tom, None, state::Condition::Always).unwrap().is_some());
+        assert_eq!(state.get(&key as &dyn StateValue).unwrap().ts(), Some(always));
+    }
+
+    #[test]
+    fn should_delete_only_if_timestamps_match() {
+        let value = HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: 1, ttl: None, deleted: false}.into());
+        let state = Default::default();
+        state.set(&value);
+
+        let key = "cat".to_string();
+
+        assert!(state.delete_if(&key, state::Condition::Ts(0)).unwrap().is_none());
+        assert!(state.get(&key as &dyn StateValue).is_some());
+
+        let ts = state.delete_if(&key, state::Condition::Ts(1)).unwrap().unwrap();
+        assert!(ts > 1);
+        assert!(state.get(&key as &dyn StateValue).is_none());
+        assert!(state.storage.read().unwrap()["cat"].deleted);
+
+        assert!(state.delete_if(&key, state::Condition::Exists).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_report_the_outcome_of_every_key() {
+        let state = Default::default();
+        state.set(&HashMap::unit("dog".to_string(), Value {value: "snoopy".into(), ts: 2, ttl: None, deleted: false}.into()));
+
+        let value = r#"{"cat": {"value": "garfield"}, "dog": {"value": "odie", "ts": 1}, "mouse": {"value": "jerry", "ts": 0, "ttl": 1}}"#;
+        let report = state.set_sync(&value).unwrap();
+        let report: serde_json::Value = serde_json::from_slice(&report.as_bytes().unwrap()).unwrap();
+
+        assert_eq!(report, serde_json::json!({"cat": "applied", "dog": "ignored", "mouse": "expired"}));
+    }
+
+    #[test]
+    fn should_reject_malformed_values() {
+        let state = Default::default();
+
+        assert!(state::State::set(&state, &"garbage").is_err());
+        assert!(state.set_sync(&r#"{"cat": "garfield"}"#).is_err());
+    }
+
+    #[test]
+    fn should_apply_patches() {
+        let value = HashMap::unit("cat".to_string(), Value {value: serde_json::json!({"name": "garfield", "age": 42}), ts: 1, ttl: None, deleted: false}.into());
+        let state = Default::default();
+        state.set(&value);
+
+        let key = "cat".to_string();
+        let value = |value: Box<dyn StateValue>| serde_json::from_slice::<serde_json::Value>(&value.as_raw_bytes().unwrap()).unwrap();
+
+        let patched = state.patch(&key, &r#"{"age": null, "color": "orange"}"#, state::Patch::Merge).unwrap().unwrap();
+        assert_eq!(value(patched), serde_json::json!({"name": "garfield", "color": "orange"}));
+
+        let patched = state.patch(&key, &r#"[{"op": "replace", "path": "/name", "value": "tom"}]"#, state::Patch::Json).unwrap().unwrap();
+        assert_eq!(value(patched), serde_json::json!({"name": "tom", "color": "orange"}));
+
+        assert!(state.patch(&key, &r#"[{"op": "test", "path": "/name", "value": "garfield"}]"#, state::Patch::Json).is_err());
+        assert!(state.patch(&"dog".to_string(), &"{}", state::Patch::Merge).unwrap().is_none());
+    }
+
+    #[test]
+    fn should_set_msgpack_values() {
+        let value: HashMap<String, Box<Value>> = HashMap::unit("cat".to_string(), Value {value: serde_json::json!({"name": "garfield"}), ts: 1, ttl: Some(60000), deleted: false}.into());
+        let encoded = value.encode(state::Format::MessagePack).unwrap();
+        assert_ne!(encoded, value.as_bytes().unwrap());
+
+        let (tx, rx) = mpsc::sync_channel(1);
+        let state = Default { tx: Some(tx), ..Default::default() };
+
+        state.set_encoded(&encoded, state::Format::MessagePack).unwrap();
+        assert_eq!(rx.recv().unwrap().as_bytes(), value.as_bytes());
+
+        assert!(state.set_encoded(&"{}".to_string(), state::Format::MessagePack).is_err());
+    }
+
+    #[test]
+    fn should_export_and_import() {
+        let first = Default::default();
+        first.set(&HashMap::unit("cat".to_string(), Value {value: "garfield".into(), ts: epoch(), ttl: Some(60000), deleted: false}.into()));
+        first.set(&HashMap::unit("dog".to_string(), Value {value: serde_json::json!({"name": "odie"}), ts: epoch(), ttl: None, deleted: false}.into()));
+        first.set(&HashMap::unit("mouse".to_string(), Value::tombstone().into()));
+
+        let mut records: Vec<Vec<u8>> = first.export().unwrap().map(|record| record.as_bytes().unwrap()).collect();
+        records.sort();
+        assert_eq!(records.len(), 2);
+        assert!(String::from_utf8_lossy(&records[0]).starts_with(r#"{"key":"cat","value":"garfield","ts":"#));
+
+        let second = Default::default();
+        let import = second.import(&records.join(&b'\n').to_vec()).unwrap();
+        assert_eq!(import, state::Import { records: 2, applied: 2 });
+        assert_eq!(second.get(&"cat".to_string()).unwrap().as_bytes(), first.get(&"cat".to_string()).unwrap().as_bytes());
+
+        let import = second.import(&records[0]).unwrap();
+        assert_eq!(import, state::Import { records: 1, applied: 0 });
+
+        assert!(second.import(&b"{\"key\":\"cow\",\"value\":1}\nnot json".to_vec()).is_err());
+        assert!(second.get(&"cow".to_string()).is_none());
+    }
+
+    #[test]
+    fn should_query_values_by_predicate() {
+        let state = Default::default();
+        let subs = [
+            ("subs/1", serde_json::json!({"plan": "pro", "active": true, "seats": 10})),
+            ("subs/2", serde_json::json!({"plan": "pro", "active": false, "seats": 3})),
+            ("subs/3", serde_json::json!({"plan": "free", "active": true, "tags": ["a", "b"]})),
+            ("users/1", serde_json::json!({"plan": "pro", "active": true})),
+        ];
+        for (key, value) in subs.iter() {
+            state.set(&HashMap::unit(key.to_string(), Value {value: value.clone(), ts: epoch(), ttl: None, deleted: false}.into()));
+        }
+
+        let query = |predicate: &str| {
+            let scan = state::Scan {prefix: "subs/".to_string(), ..std::default::Default::default()};
+            let page = state.query(&scan, predicate).unwrap();
+            let page = serde_json::from_slice::<serde_json::Value>(&page.as_bytes().unwrap()).unwrap();
+            page["values"].as_object().unwrap().keys().cloned().collect::<Vec<_>>()
+        };
+
+        assert_eq!(query(r#"value.plan == "pro" && value.active"#), vec!["subs/1"]);
+        assert_eq!(query(r#"value.plan == 'pro' && !value.active || value.tags[1] == "b""#), vec!["subs/2", "subs/3"]);
+        assert_eq!(query("value.seats >= 3 && value.seats < 10.5"), vec!["subs/1", "subs/2"]);
+        assert_eq!(query(r#"key > "subs/1" && (value.missing == null)"#), vec!["subs/2", "subs/3"]);
+        assert!(query("value.nothing").is_empty());
+
+        let scan = state::Scan {prefix: "subs/".to_string(), ..std::default::Default::default()};
+        assert!(state.query(&scan, "value.plan = 1").is_err());
+        assert!(state.query(&scan, "(value.active").is_err());
+        assert!(state.query(&scan, "plan == 1").is_err());
+
+        let nested = |depth: usize| format!("{}value.active", "!".repeat(depth));
+        assert!(state.query(&scan, &nested(64)).is_ok());
+        assert!(state.query(&scan, &nested(65)).is_err());
+        assert!(state.query(&scan, &nested(100_000)).is_err());
+        assert!(state.query(&scan, &format!("{}value.active{}", "(".repeat(100_000), ")".repeat(100_000))).is_err());
+        assert!(state.query(&scan, &vec!["value.active"; 100_000].join(" || ")).is_err());
+        assert!(state.query(&scan, &vec!["value.active"; 65].join(" && ")).is_ok());
+        assert!(state.query(&scan, &vec!["value.active"; 66].join(" && ")).is_err());
+    }
+
+    #[test]
+    fn should_maintain_indexes() {
+        let index = index::Index {name: "by_account".to_string(), prefix: "subs/".to_string(), pointer: "/account_id".to_string()};
+        let state = Default {indexes: vec![index], ..Default::default()};
+        let sub = |account: &str, ts: u64, ttl: Option<u64>| Box::new(Value {value: serde_json::json!({"account_id": account}), ts, ttl, deleted: false});
+
+        state.set(&HashMap::unit("subs/1".to_string(), sub("a", 1, None)));
+        state.set(&HashMap::unit("subs/2".to_string(), sub("a", 1, None)));
+        state.set(&HashMap::unit("subs/3".to_string(), sub("b", 1, None)));
+        state.set(&HashMap::unit("users/1".to_string(), sub("a", 1, None)));
+
+        let lookup = |account: &str, limit: Option<usize>| {
+            let scan = state::Scan {limit, ..std::default::Default::default()};
+            let page = state.lookup("by_account", &format!("\"{}\"", account), &scan).unwrap();
+            let page = serde_json::from_slice::<serde_json::Value>(&page.as_bytes().unwrap()).unwrap();
+            (page["values"].as_object().unwrap().keys().cloned().collect::<Vec<_>>(), page["next"].clone())
+        };
+
+        assert_eq!(lookup("a", None), (vec!["subs/1".to_string(), "subs/2".to_string()], serde_json::Value::Null));
+        assert_eq!(lookup("a", Some(1)), (vec!["subs/1".to_string()], serde_json::json!("subs/1")));
+
+        // moving a key to another account, deleting a key and expiring a key update the index
+        state.set(&HashMap::unit("subs/1".to_string(), sub("b", 2, None)));
+        state.set(&HashMap::unit("subs/2".to_string(), Value::tombstone().into()));
+        state.set(&HashMap::unit("subs/3".to_string(), sub("c", 0, None)));
+        assert!(lookup("a", None).0.is_empty());
+        assert_eq!(lookup("b", None).0, vec!["subs/1", "subs/3"]);
+
+        // an expired value stays in the index until it is purged, but is not returned
+        let expired = sub("b", 1, Some(1));
+        state.entries.write().unwrap().update(&state.indexes, "subs/4", None, expired.indexed());
+        state.storage.write().unwrap().insert("subs/4".to_string(), expired);
+        assert!(state.entries.read().unwrap().lookup(&state.indexes, "by_account", &"b".into()).unwrap().any(|key| key == "subs/4"));
+        assert_eq!(lookup("b", None).0, vec!["subs/1", "subs/3"]);
+        state.purge();
+        assert!(state.entries.read().unwrap().lookup(&state.indexes, "by_account", &"b".into()).unwrap().all(|key| key != "subs/4"));
+
+        assert!(state.lookup("by_plan", &"\"a\"".to_string(), &std::default::Default::default()).is_err());
+    }
+
+    #[test]
+    fn should_index_numbers_by_their_value() {
+        let index = index::Index {name: "by_seats".to_string(), prefix: "".to_string(), pointer: "/seats".to_string()};
+        let state = Default {indexes: vec![index], ..Default::default()};
+        let sub = |seats: serde_json::Value| Box::new(Value {value: serde_json::json!({"seats": seats}), ts: 1, ttl: None, deleted: false});
+
+        state.set(&HashMap::unit("subs/1".to_string(), sub(serde_json::json!(10))));
+        state.set(&HashMap::unit("subs/2".to_string(), sub(serde_json::json!(10.0))));
+        state.set(&HashMap::unit("subs/3".to_string(), sub(serde_json::json!(10.5))));
+        state.set(&HashMap::unit("subs/4".to_string(), sub(serde_json::json!([-1.0, {"max": 1e1}]))));
+        state.set(&HashMap::unit("subs/5".to_string(), sub(serde_json::json!(u64::MAX))));
+
+        let lookup = |seats: &str| {
+            let page = state.lookup("by_seats", &seats.to_string(), &std::default::Default::default()).unwrap();
+            let page = serde_json::from_slice::<serde_json::Value>(&page.as_bytes().unwrap()).unwrap();
+            page["values"].as_object().unwrap().keys().cloned().collect::<Vec<_>>()
+        };
+
+        assert_eq!(lookup("10"), vec!["subs/1", "subs/2"]);
+        assert_eq!(lookup("1e1"), vec!["subs/1", "subs/2"]);
+        assert_eq!(lookup("10.5"), vec!["subs/3"]);
+        assert_eq!(lookup(r#"[-1, {"max": 10}]"#), vec!["subs/4"]);
+        assert_eq!(lookup("18446744073709551615"), vec!["subs/5"]);
+    }
 }
@@ -0,0 +1,119 @@
+//! Secondary indexes on the values of the state.
+//!
+//! An index maps a field inside the values of the keys under a prefix to the keys that hold it,
+//! so lookups like "all the subscriptions of an account" do not scan the whole state. The field
+//! is selected by a [JSON pointer](https://tools.ietf.org/html/rfc6901):
+//!
+//! ```yaml
+//! state:
+//!   kind: Default
+//!   indexes:
+//!     - name: subs_by_account
+//!       prefix: subs/
+//!       pointer: /account_id
+//! ```
+//!
+//! The indexes are kept in memory and are updated with every merge and purge, while the storage
+//! is locked, so they always match the storage. Keys whose value does not have the field are not
+//! indexed. Any JSON value can be indexed, and a lookup matches values that are equal as JSON.
+//! Numbers are equal if their values are, so `1`, `1.0` and `1e0` match each other, also inside
+//! arrays and objects.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The configuration of an index.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Index {
+    /// The name to look the index up by.
+    pub name: String,
+
+    /// Only keys that start with this prefix are indexed.
+    #[serde(default)]
+    pub prefix: String,
+
+    /// The JSON pointer to the indexed field inside the values.
+    pub pointer: String,
+}
+
+impl Index {
+    /// Returns the term a value of `key` is indexed by, if any.
+    fn term(&self, key: &str, value: Option<&Value>) -> Option<String> {
+        if !key.starts_with(&self.prefix) {
+            return None;
+        }
+
+        value.and_then(|value| value.pointer(&self.pointer)).map(term)
+    }
+}
+
+/// The entries of the indexes, in the same order as their configuration.
+///
+/// Every index maps a term, the indexed field serialized as JSON with normalized numbers, to the
+/// keys that hold it.
+#[derive(Debug, Default)]
+pub struct Entries {
+    terms: Vec<BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl Entries {
+    /// Updates the indexes for a key whose value changed from `old` to `new`.
+    ///
+    /// `None` stands for a key that does not exist, was deleted or was purged.
+    pub fn update(&mut self, indexes: &[Index], key: &str, old: Option<&Value>, new: Option<&Value>) {
+        self.terms.resize_with(indexes.len(), BTreeMap::new);
+
+        for (index, terms) in indexes.iter().zip(self.terms.iter_mut()) {
+            let (old, new) = (index.term(key, old), index.term(key, new));
+            if old == new {
+                continue;
+            }
+
+            if let Some(old) = old {
+                if let Some(keys) = terms.get_mut(&old) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        terms.remove(&old);
+                    }
+                }
+            }
+
+            if let Some(new) = new {
+                terms.entry(new).or_default().insert(key.to_string());
+            }
+        }
+    }
+
+    /// Returns the keys that hold `value` in the index with the specified name, in lexicographical
+    /// order.
+    ///
+    /// Returns `None` if there is no such index.
+    pub fn lookup<'a>(&'a self, indexes: &[Index], name: &str, value: &Value) -> Option<impl Iterator<Item = &'a String>> {
+        let position = indexes.iter().position(|index| index.name == name)?;
+        let keys = self.terms.get(position).and_then(|terms| terms.get(&term(value)));
+
+        Some(keys.into_iter().flatten())
+    }
+}
+
+/// Returns the term of an indexed field.
+fn term(value: &Value) -> String {
+    normalize(value).to_string()
+}
+
+/// Returns the value with every number that has no fraction written as an integer.
+fn normalize(value: &Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            Some(f) if number.is_f64() && f.fract() == 0.0 && f < 0.0 && f >= i64::MIN as f64 => Value::from(f as i64),
+            Some(f) if number.is_f64() && f.fract() == 0.0 && f >= 0.0 && f < u64::MAX as f64 => Value::from(f as u64),
+            _ => value.clone(),
+        },
+        Value::Array(values) => Value::Array(values.iter().map(normalize).collect()),
+        Value::Object(fields) => {
+            Value::Object(fields.iter().map(|(name, value)| (name.clone(), normalize(value))).collect())
+        }
+        _ => value.clone(),
+    }
+}
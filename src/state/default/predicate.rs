@@ -0,0 +1,392 @@
+//! Predicates over the entries of the state.
+//!
+//! A predicate is a boolean expression over the key, the value, the timestamp and the TTL of an
+//! entry, for example:
+//!
+//! ```text
+//! value.plan == "pro" && value.active && !(value.seats < 5)
+//! ```
+//!
+//! # Grammar
+//!
+//! - Paths start with `key`, `value`, `ts` or `ttl` and select nested fields with `.field`,
+//!   `["field"]` or `[index]`, for example `value.address.city` or `value.items[0]`. A path that
+//!   does not exist is `null`.
+//! - Literals are strings (in double or single quotes), numbers, `true`, `false` and `null`.
+//! - Comparisons are `==`, `!=`, `<`, `<=`, `>` and `>=`. Numbers are compared by their value and
+//!   strings lexicographically. Ordering anything else is false.
+//! - A path or a literal on its own is true unless it is `null` or `false`.
+//! - Conditions are combined with `&&`, `||`, `!` and parentheses. `&&` binds tighter than `||`.
+//!
+//! A predicate can be nested up to 64 levels deep. Every `!` and pair of parentheses counts as a
+//! level, and so does every `&&` and `||` of a chain, so a chain of 100 `||` is too deep as well.
+
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// The maximum depth of a predicate, so parsing and matching it cannot overflow the stack.
+const MAX_DEPTH: usize = 64;
+
+/// A parsed predicate and its depth.
+type Parsed = Result<(Predicate, usize), String>;
+
+/// A parsed predicate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Predicate {
+    Or(Box<Predicate>, Box<Predicate>),
+    And(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+    Compare(Operator, Operand, Operand),
+    Truthy(Operand),
+}
+
+/// A comparison operator.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// A side of a comparison.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Path(Root, Vec<Segment>),
+    Literal(Value),
+}
+
+/// The part of an entry a path starts from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Root {
+    Key,
+    Value,
+    Ts,
+    Ttl,
+}
+
+/// A step of a path.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Field(String),
+    Index(usize),
+}
+
+/// An entry a predicate is evaluated against.
+pub struct Entry<'a> {
+    pub key: &'a str,
+    pub value: &'a Value,
+    pub ts: u64,
+    pub ttl: Option<u64>,
+}
+
+impl Predicate {
+    /// Parses a predicate.
+    pub fn parse(input: &str) -> Result<Predicate, String> {
+        let mut parser = Parser { tokens: tokenize(input)?.into_iter().peekable(), nesting: 0 };
+        let (predicate, _) = parser.or()?;
+
+        match parser.tokens.next() {
+            Some(token) => Err(format!("unexpected {:?} in predicate", token)),
+            None => Ok(predicate),
+        }
+    }
+
+    /// Returns true if the entry matches the predicate.
+    pub fn matches(&self, entry: &Entry<'_>) -> bool {
+        match self {
+            Predicate::Or(left, right) => left.matches(entry) || right.matches(entry),
+            Predicate::And(left, right) => left.matches(entry) && right.matches(entry),
+            Predicate::Not(predicate) => !predicate.matches(entry),
+            Predicate::Compare(operator, left, right) => compare(*operator, &left.resolve(entry), &right.resolve(entry)),
+            Predicate::Truthy(operand) => !matches!(operand.resolve(entry), Value::Null | Value::Bool(false)),
+        }
+    }
+}
+
+impl Operand {
+    /// Returns the value of the operand for the entry.
+    fn resolve(&self, entry: &Entry<'_>) -> Value {
+        let (root, path) = match self {
+            Operand::Literal(value) => return value.clone(),
+            Operand::Path(root, path) => (root, path),
+        };
+
+        let root = match root {
+            Root::Key => return if path.is_empty() { Value::from(entry.key) } else { Value::Null },
+            Root::Ts => return if path.is_empty() { Value::from(entry.ts) } else { Value::Null },
+            Root::Ttl => return if path.is_empty() { Value::from(entry.ttl) } else { Value::Null },
+            Root::Value => entry.value,
+        };
+
+        let mut current = root;
+        for segment in path {
+            let next = match segment {
+                Segment::Field(field) => current.get(field),
+                Segment::Index(index) => current.get(index),
+            };
+
+            current = match next {
+                Some(next) => next,
+                None => return Value::Null,
+            };
+        }
+
+        current.clone()
+    }
+}
+
+fn compare(operator: Operator, left: &Value, right: &Value) -> bool {
+    let ordering = match (left, right) {
+        (Value::Number(left), Value::Number(right)) => left.as_f64().partial_cmp(&right.as_f64()),
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        _ => None,
+    };
+
+    match operator {
+        Operator::Eq => ordering.map_or(left == right, |ordering| ordering == Ordering::Equal),
+        Operator::Ne => ordering.map_or(left != right, |ordering| ordering != Ordering::Equal),
+        Operator::Lt => ordering == Some(Ordering::Less),
+        Operator::Le => matches!(ordering, Some(Ordering::Less) | Some(Ordering::Equal)),
+        Operator::Gt => ordering == Some(Ordering::Greater),
+        Operator::Ge => matches!(ordering, Some(Ordering::Greater) | Some(Ordering::Equal)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Value),
+    Operator(Operator),
+    And,
+    Or,
+    Not,
+    Dot,
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '.' => single(&mut chars, Token::Dot),
+            '(' => single(&mut chars, Token::LeftParen),
+            ')' => single(&mut chars, Token::RightParen),
+            '[' => single(&mut chars, Token::LeftBracket),
+            ']' => single(&mut chars, Token::RightBracket),
+            '&' | '|' => {
+                chars.next();
+                if chars.next() != Some(c) {
+                    return Err(format!("expected {}{} in predicate", c, c));
+                }
+                if c == '&' { Token::And } else { Token::Or }
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.peek() == Some(&'=');
+                if eq {
+                    chars.next();
+                }
+
+                match (c, eq) {
+                    ('=', true) => Token::Operator(Operator::Eq),
+                    ('!', true) => Token::Operator(Operator::Ne),
+                    ('!', false) => Token::Not,
+                    ('<', true) => Token::Operator(Operator::Le),
+                    ('<', false) => Token::Operator(Operator::Lt),
+                    ('>', true) => Token::Operator(Operator::Ge),
+                    ('>', false) => Token::Operator(Operator::Gt),
+                    _ => return Err("expected == in predicate".to_string()),
+                }
+            }
+            '"' | '\'' => Token::Literal(Value::String(string(&mut chars)?)),
+            c if c.is_ascii_digit() || c == '-' => Token::Literal(number(&mut chars)?),
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek().filter(|c| c.is_alphanumeric() || **c == '_') {
+                    ident.push(c);
+                    chars.next();
+                }
+
+                match ident.as_str() {
+                    "true" => Token::Literal(Value::Bool(true)),
+                    "false" => Token::Literal(Value::Bool(false)),
+                    "null" => Token::Literal(Value::Null),
+                    _ => Token::Ident(ident),
+                }
+            }
+            c => return Err(format!("unexpected '{}' in predicate", c)),
+        };
+
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn single(chars: &mut Peekable<Chars<'_>>, token: Token) -> Token {
+    chars.next();
+    token
+}
+
+/// Reads a quoted string. Backslash escapes the next character.
+fn string(chars: &mut Peekable<Chars<'_>>) -> Result<String, String> {
+    let quote = chars.next();
+    let mut string = String::new();
+
+    loop {
+        match chars.next() {
+            Some('\\') => string.extend(chars.next()),
+            Some(c) if Some(c) == quote => return Ok(string),
+            Some(c) => string.push(c),
+            None => return Err("unterminated string in predicate".to_string()),
+        }
+    }
+}
+
+fn number(chars: &mut Peekable<Chars<'_>>) -> Result<Value, String> {
+    let mut number = String::new();
+    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+        number.push(c);
+        chars.next();
+    }
+
+    serde_json::from_str::<serde_json::Number>(&number)
+        .map(Value::Number)
+        .map_err(|_| format!("invalid number {} in predicate", number))
+}
+
+/// A recursive descent parser.
+///
+/// The parser keeps track of how deep it is nested in `!` and parentheses, so it fails before it
+/// recurses too deep, and of the depth of the predicate it builds, so matching it cannot recurse
+/// too deep either.
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+    nesting: usize,
+}
+
+impl Parser {
+    fn or(&mut self) -> Parsed {
+        let (mut predicate, mut depth) = self.and()?;
+        while self.next_if(&Token::Or) {
+            let (right, right_depth) = self.and()?;
+            predicate = Predicate::Or(Box::new(predicate), Box::new(right));
+            depth = deeper(depth.max(right_depth))?;
+        }
+
+        Ok((predicate, depth))
+    }
+
+    fn and(&mut self) -> Parsed {
+        let (mut predicate, mut depth) = self.not()?;
+        while self.next_if(&Token::And) {
+            let (right, right_depth) = self.not()?;
+            predicate = Predicate::And(Box::new(predicate), Box::new(right));
+            depth = deeper(depth.max(right_depth))?;
+        }
+
+        Ok((predicate, depth))
+    }
+
+    fn not(&mut self) -> Parsed {
+        if self.next_if(&Token::Not) {
+            self.nesting = deeper(self.nesting)?;
+            let (predicate, depth) = self.not()?;
+            self.nesting -= 1;
+            return Ok((Predicate::Not(Box::new(predicate)), deeper(depth)?));
+        }
+
+        if self.next_if(&Token::LeftParen) {
+            self.nesting = deeper(self.nesting)?;
+            let parsed = self.or()?;
+            self.expect(&Token::RightParen)?;
+            self.nesting -= 1;
+            return Ok(parsed);
+        }
+
+        let left = self.operand()?;
+        match self.tokens.peek() {
+            Some(Token::Operator(operator)) => {
+                let operator = *operator;
+                self.tokens.next();
+                Ok((Predicate::Compare(operator, left, self.operand()?), 0))
+            }
+            _ => Ok((Predicate::Truthy(left), 0)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let root = match self.tokens.next() {
+            Some(Token::Literal(value)) => return Ok(Operand::Literal(value)),
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "key" => Root::Key,
+                "value" => Root::Value,
+                "ts" => Root::Ts,
+                "ttl" => Root::Ttl,
+                _ => return Err(format!("unknown '{}' in predicate, expected key, value, ts or ttl", ident)),
+            },
+            Some(token) => return Err(format!("unexpected {:?} in predicate", token)),
+            None => return Err("unexpected end of predicate".to_string()),
+        };
+
+        let mut path = Vec::new();
+        loop {
+            if self.next_if(&Token::Dot) {
+                match self.tokens.next() {
+                    Some(Token::Ident(field)) => path.push(Segment::Field(field)),
+                    _ => return Err("expected a field name after '.' in predicate".to_string()),
+                }
+            } else if self.next_if(&Token::LeftBracket) {
+                match self.tokens.next() {
+                    Some(Token::Literal(Value::String(field))) => path.push(Segment::Field(field)),
+                    Some(Token::Literal(Value::Number(index))) if index.is_u64() => {
+                        path.push(Segment::Index(index.as_u64().unwrap_or_default() as usize))
+                    }
+                    _ => return Err("expected a field name or an index inside [] in predicate".to_string()),
+                }
+                self.expect(&Token::RightBracket)?;
+            } else {
+                return Ok(Operand::Path(root, path));
+            }
+        }
+    }
+
+    fn next_if(&mut self, token: &Token) -> bool {
+        if self.tokens.peek() == Some(token) {
+            self.tokens.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &Token) -> Result<(), String> {
+        if self.next_if(token) {
+            Ok(())
+        } else {
+            Err(format!("expected {:?} in predicate", token))
+        }
+    }
+}
+
+/// Returns the depth one level deeper, or fails if it is deeper than [MAX_DEPTH].
+fn deeper(depth: usize) -> Result<usize, String> {
+    match depth + 1 {
+        depth if depth > MAX_DEPTH => Err(format!("the predicate is nested deeper than {} levels", MAX_DEPTH)),
+        depth => Ok(depth),
+    }
+}
@@ -0,0 +1,302 @@
+//! Webhooks that push the changes of the state to an HTTP endpoint.
+//!
+//! Instead of polling the agent, an app can have its changes pushed. Every change that is
+//! committed to the state (by a merge or by the purger, once a key expires) of a key that starts
+//! with one of the `prefixes` is queued, and the queue is sent in batches with a `POST` request:
+//!
+//! ```yaml
+//! state:
+//!   kind: Default
+//!   webhooks:
+//!     - url: http://localhost:8080/c19/changes
+//!       prefixes: ["users/", "flags/"]
+//!       debounce: 200
+//! ```
+//!
+//! ```json
+//! {"changes":[{"id":42,"kind":"set","key":"users/42","ts":1601241450390,"value":{"ts":1601241450390,"ttl":null,"value":"garfield"}}]}
+//! ```
+//!
+//! `kind` is `set`, `delete` or `expire`, and `value` is `null` unless the key was set.
+//!
+//! # Delivery
+//! Once a change is queued, the webhook waits `debounce` milliseconds for more changes and then
+//! sends up to `max_batch` of them. Only the latest change of every key in a batch is sent, so a
+//! key that changes many times in a row is sent once. A batch that fails (or gets a response
+//! other than `2xx`) is retried up to `retries` times, waiting `retry_interval` milliseconds
+//! before the first retry and twice as long before every next one, and is then dropped.
+//!
+//! The queue holds up to `max_queue` changes. Changes that arrive while the queue is full are
+//! dropped and reported, so a slow or unavailable endpoint never holds back the state.
+
+use crate::state::{Change, ChangeKind};
+use log::{debug, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{delay_for, Duration};
+
+/// The configuration of a webhook.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Webhook {
+    /// The URL to send the changes to.
+    url: String,
+
+    /// Only changes of keys that start with one of these prefixes are sent. All keys if empty.
+    ///
+    /// Default value: empty.
+    prefixes: Vec<String>,
+
+    /// The time in milliseconds to wait for more changes before sending a batch.
+    ///
+    /// Default value: 100 milliseconds.
+    debounce: u64,
+
+    /// The maximum number of changes in a batch.
+    ///
+    /// Default value: 100.
+    max_batch: usize,
+
+    /// The maximum number of changes that wait to be sent.
+    ///
+    /// Default value: 10000.
+    max_queue: usize,
+
+    /// The number of times a failed batch is retried.
+    ///
+    /// Default value: 3.
+    retries: u32,
+
+    /// The time in milliseconds to wait before the first retry of a batch.
+    ///
+    /// Default value: 1 second (1000 milliseconds).
+    retry_interval: u64,
+
+    /// The timeout in milliseconds of a request to the endpoint.
+    ///
+    /// Default value: 5 seconds (5000 milliseconds).
+    timeout: u64,
+
+    /// The changes that wait to be sent.
+    #[serde(skip_serializing, skip_deserializing)]
+    queue: Arc<Queue>,
+}
+
+/// The queue of a webhook.
+#[derive(Debug, Default)]
+struct Queue {
+    /// The queued changes and the number of changes that were dropped since the last batch.
+    changes: Mutex<(VecDeque<Change>, usize)>,
+
+    /// Wakes the sender up when a change is queued.
+    notify: Notify,
+}
+
+impl std::default::Default for Webhook {
+    fn default() -> Self {
+        Webhook {
+            url: String::new(),
+            prefixes: Vec::new(),
+            debounce: 100,
+            max_batch: 100,
+            max_queue: 10000,
+            retries: 3,
+            retry_interval: 1000,
+            timeout: 5000,
+            queue: std::default::Default::default(),
+        }
+    }
+}
+
+impl Webhook {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Queues the change if its key matches the prefixes of the webhook.
+    ///
+    /// Never blocks. The change is dropped if the queue is full.
+    pub fn push(&self, change: &Change) {
+        if !self.prefixes.is_empty() && !self.prefixes.iter().any(|prefix| change.key.starts_with(prefix)) {
+            return;
+        }
+
+        let mut changes = self.queue.changes.lock().unwrap();
+        if changes.0.len() >= self.max_queue {
+            changes.1 += 1;
+            return;
+        }
+
+        changes.0.push_back(change.clone());
+        self.queue.notify.notify();
+    }
+
+    /// Sends the queued changes to the endpoint for as long as the state lives.
+    pub async fn run(self) {
+        let client = match Client::builder().timeout(Duration::from_millis(self.timeout)).build() {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("Failed to create the client of the webhook {}; {}", self.url, e);
+                return;
+            }
+        };
+
+        loop {
+            self.queue.notify.notified().await;
+            delay_for(Duration::from_millis(self.debounce)).await;
+
+            loop {
+                let batch = self.batch();
+                if batch.is_empty() {
+                    break;
+                }
+
+                self.send(&client, batch).await;
+            }
+        }
+    }
+
+    /// Takes the next batch from the queue, keeping only the latest change of every key.
+    fn batch(&self) -> Vec<Change> {
+        let mut changes = self.queue.changes.lock().unwrap();
+        if changes.1 > 0 {
+            warn!("The queue of the webhook {} was full, {} changes were dropped", self.url, changes.1);
+            changes.1 = 0;
+        }
+
+        let len = changes.0.len().min(self.max_batch.max(1));
+        let mut batch: Vec<Change> = Vec::with_capacity(len);
+        for change in changes.0.drain(..len) {
+            batch.retain(|queued| queued.key != change.key);
+            batch.push(change);
+        }
+
+        batch
+    }
+
+    /// Sends a batch, retrying it if it fails.
+    async fn send(&self, client: &Client, batch: Vec<Change>) {
+        let body = json!({"changes": batch.into_iter().map(payload).collect::<Vec<_>>()});
+        let mut interval = self.retry_interval;
+
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                delay_for(Duration::from_millis(interval)).await;
+                interval = interval.saturating_mul(2);
+            }
+
+            let result = client.post(&self.url).json(&body).send().await;
+            match result {
+                Ok(response) if response.status().is_success() => {
+                    debug!("Sent a batch of changes to the webhook {}", self.url);
+                    return;
+                }
+                Ok(response) => warn!("The webhook {} responded with {}", self.url, response.status()),
+                Err(e) => warn!("Failed to call the webhook {}; {}", self.url, e),
+            }
+        }
+
+        warn!("Dropped a batch of changes to the webhook {} after {} retries", self.url, self.retries);
+    }
+}
+
+/// Returns the JSON payload of a change.
+fn payload(change: Change) -> serde_json::Value {
+    let value: serde_json::Value = change
+        .value
+        .and_then(|value| value.as_bytes())
+        .and_then(|value| serde_json::from_slice(&value).ok())
+        .unwrap_or_default();
+
+    let kind = match change.kind {
+        ChangeKind::Set => "set",
+        ChangeKind::Delete => "delete",
+        ChangeKind::Expire => "expire",
+    };
+
+    json!({"id": change.id, "kind": kind, "key": change.key, "ts": change.ts, "value": value})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(yaml: &str) -> Webhook {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    fn change(id: u64, key: &str) -> Change {
+        Change { id, kind: ChangeKind::Delete, key: key.to_string(), ts: id, value: None }
+    }
+
+    fn queued(webhook: &Webhook) -> (Vec<u64>, usize) {
+        let changes = webhook.queue.changes.lock().unwrap();
+        (changes.0.iter().map(|change| change.id).collect(), changes.1)
+    }
+
+    #[test]
+    fn should_queue_changes_of_matching_keys() {
+        let filtered = webhook(r#"{url: "http://localhost", prefixes: ["cats/", "dogs/"]}"#);
+        filtered.push(&change(1, "cats/garfield"));
+        filtered.push(&change(2, "mice/jerry"));
+        filtered.push(&change(3, "dogs/odie"));
+        filtered.push(&change(4, "cats"));
+
+        assert_eq!(queued(&filtered), (vec![1, 3], 0));
+
+        let all = webhook(r#"{url: "http://localhost"}"#);
+        all.push(&change(1, "cats/garfield"));
+        all.push(&change(2, "mice/jerry"));
+
+        assert_eq!(queued(&all), (vec![1, 2], 0));
+    }
+
+    #[test]
+    fn should_drop_changes_when_the_queue_is_full() {
+        let webhook = webhook(r#"{url: "http://localhost", max_queue: 2}"#);
+        for id in 1..=5 {
+            webhook.push(&change(id, "cats/garfield"));
+        }
+
+        assert_eq!(queued(&webhook), (vec![1, 2], 3));
+
+        // the drops are reported and reset with the next batch
+        assert_eq!(webhook.batch().len(), 1);
+        assert_eq!(queued(&webhook), (vec![], 0));
+
+        webhook.push(&change(6, "cats/garfield"));
+        assert_eq!(queued(&webhook), (vec![6], 0));
+    }
+
+    #[test]
+    fn should_send_the_latest_change_of_every_key() {
+        let webhook = webhook(r#"{url: "http://localhost", max_batch: 4}"#);
+        for (id, key) in [(1, "cats/garfield"), (2, "dogs/odie"), (3, "cats/garfield"), (4, "cats/nermal")].iter() {
+            webhook.push(&change(*id, key));
+        }
+        webhook.push(&change(5, "cats/garfield"));
+        webhook.push(&change(6, "dogs/odie"));
+
+        let ids = |batch: Vec<Change>| batch.into_iter().map(|change| change.id).collect::<Vec<_>>();
+
+        // the batch takes up to max_batch changes of the queue and keeps the latest of every key
+        assert_eq!(ids(webhook.batch()), vec![2, 3, 4]);
+        assert_eq!(ids(webhook.batch()), vec![5, 6]);
+        assert!(webhook.batch().is_empty());
+    }
+
+    #[test]
+    fn should_take_at_least_one_change_per_batch() {
+        let webhook = webhook(r#"{url: "http://localhost", max_batch: 0}"#);
+        webhook.push(&change(1, "cats/garfield"));
+        webhook.push(&change(2, "dogs/odie"));
+
+        assert_eq!(webhook.batch().len(), 1);
+        assert_eq!(webhook.batch().len(), 1);
+        assert!(webhook.batch().is_empty());
+    }
+}